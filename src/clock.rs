@@ -0,0 +1,336 @@
+use crate::control::{Reply, Transport};
+use crate::prefs::InternalClockPrefs;
+use alsa::seq::{Addr, EvQueueControl, Event, EventType, PortCap, PortType, QueueTempo, Seq};
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::ffi::CString;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// ALSA client & port name of the internal clock. Unlike autoroute's other clients,
+/// this one is a regular port that roles and routes can connect to.
+pub const CLOCK_NAME: &str = "autoroute2 clock";
+
+/// MIDI clock resolution, ticks per quarter note.
+const PPQN: i32 = 24;
+
+/// How many ticks to schedule ahead of the queue.
+/// Transport messages can't go out sooner than that.
+const LOOKAHEAD: u32 = 4;
+
+/// How often to top up the queue, and check for transport commands.
+const REFILL_DELAY: Duration = Duration::from_millis(10);
+
+/// Ticks closer than this are copies of the same tick.
+/// Real ticks are at least 8 ms apart, even at 300 BPM.
+const SAME_TICK: Duration = Duration::from_millis(1);
+
+/// Handle to the internal clock thread.
+pub struct InternalClock {
+    tx: Sender<(Transport, Sender<Reply>)>,
+}
+
+impl InternalClock {
+    pub fn start(prefs: &InternalClockPrefs) -> Self {
+        let (tx, rx) = mpsc::channel();
+        let tempo = prefs.tempo;
+        thread::spawn(move || {
+            if let Err(e) = run(tempo, rx) {
+                eprintln!("Internal clock stopped: {}", e);
+            }
+        });
+        InternalClock { tx }
+    }
+
+    /// Send a start, stop or continue message. The outcome is sent on `reply`.
+    pub fn transport(&self, transport: Transport, reply: Sender<Reply>) {
+        if let Err(e) = self.tx.send((transport, reply)) {
+            let _ = (e.0).1.send(Err("Internal clock is not running".to_string()));
+        }
+    }
+}
+
+/// Tick forever, at the configured tempo. Transport messages go out right before a tick.
+fn run(tempo: f64, rx: Receiver<(Transport, Sender<Reply>)>) -> Result<(), Box<dyn Error>> {
+    let seq = Seq::open(None, None, false)?;
+    let name = CString::new(CLOCK_NAME)?;
+    seq.set_client_name(&name)?;
+    let port = seq.create_simple_port(
+        &name,
+        PortCap::READ | PortCap::SUBS_READ,
+        PortType::MIDI_GENERIC | PortType::APPLICATION,
+    )?;
+
+    let queue = seq.alloc_named_queue(&name)?;
+    let queue_tempo = QueueTempo::empty()?;
+    queue_tempo.set_tempo((60_000_000.0 / tempo).round() as u32);
+    queue_tempo.set_ppq(PPQN);
+    seq.set_queue_tempo(queue, &queue_tempo)?;
+    seq.control_queue(queue, EventType::Start, 0, None)?;
+    seq.drain_output()?;
+
+    let send = |event_type: EventType, tick: u32| {
+        let mut event = Event::new(event_type, &EvQueueControl { queue, value: () });
+        event.set_source(port);
+        event.set_subs();
+        event.schedule_tick(queue, false, tick);
+        seq.event_output(&mut event)
+    };
+
+    let mut next_tick = 0;
+    loop {
+        let now = seq.get_queue_status(queue)?.get_tick_time();
+        while next_tick <= now + LOOKAHEAD {
+            send(EventType::Clock, next_tick)?;
+            next_tick += 1;
+        }
+        seq.drain_output()?;
+
+        match rx.recv_timeout(REFILL_DELAY) {
+            Ok((transport, reply)) => {
+                let event_type = match transport {
+                    Transport::Start => EventType::Start,
+                    Transport::Stop => EventType::Stop,
+                    Transport::Continue => EventType::Continue,
+                };
+                let sent = send(event_type, next_tick).and_then(|_| seq.drain_output());
+                let _ = reply.send(match sent {
+                    Ok(_) => Ok(format!("Internal clock {:?}", transport)),
+                    Err(e) => Err(e.to_string()),
+                });
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+}
+
+/// Picks which clock source to follow: the first one, in order of preference,
+/// that ticked within the timeout. Ticks from the others are held back, and so are their
+/// start, stop, continue and song position while another source is followed.
+#[derive(Default)]
+pub struct Failover {
+    /// Present clock sources, preferred first
+    sources: Vec<(Addr, String)>,
+    timeout: Duration,
+    last_tick: HashMap<Addr, Instant>,
+    following: Option<Addr>,
+}
+
+impl Failover {
+    pub fn set_sources(&mut self, sources: Vec<(Addr, String)>, timeout: Duration) {
+        self.last_tick.retain(|addr, _| sources.iter().any(|(source, _)| source == addr));
+        self.sources = sources;
+        self.timeout = timeout;
+        // a source that went away stops being followed right now, not after the timeout
+        self.update(Instant::now());
+    }
+
+    /// True if a clock message from this sender should go through.
+    /// Messages from ports that are not clock sources always do.
+    /// Transport goes through when nothing else is followed, since devices often send
+    /// start or song position before their first tick.
+    pub fn admits(&mut self, sender: Addr, event_type: EventType) -> bool {
+        if !self.sources.iter().any(|(source, _)| *source == sender) {
+            return true;
+        }
+        let now = Instant::now();
+        if event_type != EventType::Clock {
+            self.update(now);
+            return self.following.map(|f| f == sender).unwrap_or(true);
+        }
+        self.last_tick.insert(sender, now);
+        self.update(now);
+        self.following == Some(sender)
+    }
+
+    /// The clock source whose ticks go through, if any is ticking.
+    pub fn following(&mut self) -> Option<Addr> {
+        self.update(Instant::now());
+        self.following
+    }
+
+    fn update(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        let last_tick = &self.last_tick;
+        let ticking = self.sources.iter().find(|(source, _)| {
+            last_tick.get(source).map(|t| now.duration_since(*t) < timeout).unwrap_or(false)
+        });
+        let following = ticking.map(|(source, _)| *source);
+        if following != self.following {
+            match ticking {
+                Some((_, name)) => eprintln!("Following clock from {}", name),
+                None => eprintln!("No clock source ticking"),
+            }
+            self.following = following;
+        }
+    }
+}
+
+/// Tempo of the clock reaching the routed devices: the followed clock source's,
+/// or else that of the first watched port heard ticking, until it stops.
+#[derive(Default)]
+pub struct ClockMeter {
+    /// Watched ports, by address
+    names: HashMap<Addr, String>,
+    timeout: Duration,
+    measured: Option<Addr>,
+    last_tick: Option<Instant>,
+    meter: TempoMeter,
+}
+
+impl ClockMeter {
+    pub fn set_names(&mut self, names: HashMap<Addr, String>) {
+        self.names = names;
+    }
+
+    /// How long the measured port can go without ticking before another one is measured.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// A tick from `sender`. Only the `followed` clock source counts, if there is one.
+    pub fn tick(&mut self, sender: Addr, now: Instant, followed: Option<Addr>) {
+        if followed.map(|f| f != sender).unwrap_or(false) {
+            return;
+        }
+        if self.measured != Some(sender) {
+            if followed.is_none() && !self.is_stale(now) {
+                return;
+            }
+            self.measured = Some(sender);
+            self.meter = TempoMeter::default();
+        }
+        self.last_tick = Some(now);
+        self.meter.tick(now);
+    }
+
+    fn is_stale(&self, now: Instant) -> bool {
+        match (self.measured, self.last_tick) {
+            (Some(_), Some(last)) => now.duration_since(last) >= self.timeout,
+            _ => true,
+        }
+    }
+
+    /// Which port the clock comes from, at what tempo.
+    pub fn status(&self, now: Instant) -> String {
+        let measured = match self.measured {
+            Some(measured) if !self.is_stale(now) => measured,
+            _ => return "No clock".to_string(),
+        };
+        let name = match self.names.get(&measured) {
+            Some(name) => name.clone(),
+            None => format!("{}:{}", measured.client, measured.port),
+        };
+        match self.meter.tempo() {
+            Some((bpm, jitter)) => {
+                format!("{} {:.1} BPM, jitter {:.1} ms", name, bpm, jitter.as_secs_f64() * 1000.0)
+            }
+            None => format!("{} measuring", name),
+        }
+    }
+}
+
+/// Tempo & jitter over the last beat's worth of ticks.
+#[derive(Default)]
+struct TempoMeter {
+    last: Option<Instant>,
+    intervals: VecDeque<Duration>,
+}
+
+impl TempoMeter {
+    fn tick(&mut self, now: Instant) {
+        if let Some(last) = self.last {
+            let interval = now.duration_since(last);
+            if interval < SAME_TICK {
+                return;
+            }
+            self.intervals.push_back(interval);
+            if self.intervals.len() > PPQN as usize {
+                self.intervals.pop_front();
+            }
+        }
+        self.last = Some(now);
+    }
+
+    /// BPM and standard deviation of tick intervals, once a few ticks came in.
+    fn tempo(&self) -> Option<(f64, Duration)> {
+        if self.intervals.len() < 2 {
+            return None;
+        }
+        let count = self.intervals.len() as f64;
+        let mean = self.intervals.iter().map(Duration::as_secs_f64).sum::<f64>() / count;
+        let variance =
+            self.intervals.iter().map(|i| (i.as_secs_f64() - mean).powi(2)).sum::<f64>() / count;
+        Some((60.0 / (mean * PPQN as f64), Duration::from_secs_f64(variance.sqrt())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: Addr = Addr { client: 20, port: 0 };
+    const B: Addr = Addr { client: 21, port: 0 };
+
+    fn failover() -> Failover {
+        let mut failover = Failover::default();
+        let sources = vec![(A, "A".to_string()), (B, "B".to_string())];
+        failover.set_sources(sources, Duration::from_secs(10));
+        failover
+    }
+
+    #[test]
+    fn start_before_first_tick() {
+        let mut failover = failover();
+        assert!(failover.admits(B, EventType::Songpos));
+        assert!(failover.admits(B, EventType::Start));
+        assert!(failover.admits(B, EventType::Clock));
+    }
+
+    #[test]
+    fn backup_held_while_preferred_ticks() {
+        let mut failover = failover();
+        assert!(failover.admits(A, EventType::Clock));
+        assert!(!failover.admits(B, EventType::Clock));
+        assert!(!failover.admits(B, EventType::Start));
+        assert!(failover.admits(A, EventType::Stop));
+    }
+
+    #[test]
+    fn meters_plain_clock() {
+        let mut meter = ClockMeter::default();
+        meter.set_timeout(Duration::from_millis(500));
+        meter.set_names(vec![(A, "Pyramid".to_string())].into_iter().collect());
+        // 120 BPM is a tick every 1/48 s
+        let interval = Duration::from_secs(1) / 48;
+        let start = Instant::now();
+        for i in 0..10 {
+            meter.tick(A, start + interval * i, None);
+            // another port ticking meanwhile is not measured
+            meter.tick(B, start + interval * i + Duration::from_millis(3), None);
+        }
+        let status = meter.status(start + interval * 10);
+        assert!(status.starts_with("Pyramid 120.0 BPM"), "{}", status);
+        assert_eq!(meter.status(start + Duration::from_secs(5)), "No clock");
+    }
+
+    #[test]
+    fn meters_followed_source() {
+        let mut meter = ClockMeter::default();
+        meter.set_timeout(Duration::from_millis(500));
+        let now = Instant::now();
+        meter.tick(A, now, None);
+        meter.tick(B, now + Duration::from_millis(10), Some(B));
+        meter.tick(A, now + Duration::from_millis(20), Some(B));
+        assert_eq!(meter.measured, Some(B));
+    }
+
+    #[test]
+    fn other_ports_always_admitted() {
+        let mut failover = failover();
+        assert!(failover.admits(A, EventType::Clock));
+        assert!(failover.admits(Addr { client: 30, port: 0 }, EventType::Clock));
+    }
+}
@@ -0,0 +1,148 @@
+use crate::tui::event::{Event, EventSource};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::net::Shutdown;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::time::Duration;
+
+const SOCKET_FILE: &str = "control.sock";
+
+/// How long a client waits for the daemon to act on a request.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the daemon waits for a client to send its whole request, or to take the reply.
+/// Requests are handled one at a time, a stalled client must not hold up the others.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Instructions for a running daemon, from the CLI or the TUI.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub enum Request {
+    /// Shift notes of the transposable routes from a port (alias or name),
+    /// relative to the current shift
+    Shift { from: String, semitones: i32 },
+    /// Drive the internal clock
+    Clock(Transport),
+    /// Which clock source is followed, at what tempo
+    Tempo,
+    /// The links being served, as YAML `LinkState`s
+    Links,
+}
+
+/// Transport messages of the internal clock.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum Transport {
+    Start,
+    Stop,
+    Continue,
+}
+
+impl FromStr for Transport {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "start" => Ok(Transport::Start),
+            "stop" => Ok(Transport::Stop),
+            "continue" => Ok(Transport::Continue),
+            _ => Err(format!("{} is not one of start, stop, continue", s)),
+        }
+    }
+}
+
+pub type Reply = Result<String, String>;
+
+pub fn socket_path(state_dir: &Path) -> PathBuf {
+    state_dir.join(SOCKET_FILE)
+}
+
+/// Accept requests on the control socket and pass them on as events, one connection at a time.
+/// Each connection carries a single request and its reply.
+pub fn listen(path: PathBuf) -> EventSource {
+    Box::new(move |tx| {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // left over by a previous run
+        if path.exists() {
+            fs::remove_file(&path)?;
+        }
+        let listener = UnixListener::bind(&path)?;
+        for stream in listener.incoming() {
+            // one client going wrong is no reason to stop serving the others
+            let accepted = stream.and_then(|stream| {
+                stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+                stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+                Ok(stream)
+            });
+            let mut stream = match accepted {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("Could not accept control connection: {}", e);
+                    continue;
+                }
+            };
+            let reply = match serde_yaml::from_reader(&stream) {
+                Ok(request) => {
+                    let (reply_tx, reply_rx) = mpsc::channel();
+                    tx.send(Event::Control(request, reply_tx))?;
+                    reply_rx.recv_timeout(REPLY_TIMEOUT).unwrap_or_else(|e| Err(e.to_string()))
+                }
+                Err(e) => Err(format!("Bad request: {}", e)),
+            };
+            if let Err(e) = serde_yaml::to_writer(&mut stream, &reply) {
+                eprintln!("Could not reply to control request: {}", e)
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Send a request to the daemon running with this state dir and wait for its reply.
+pub fn send(state_dir: &Path, request: &Request) -> Result<String, Box<dyn Error>> {
+    let path = socket_path(state_dir);
+    let mut stream = UnixStream::connect(&path)
+        .map_err(|e| format!("No daemon listening on {}: {}", path.display(), e))?;
+    stream.set_read_timeout(Some(REPLY_TIMEOUT * 2))?;
+    serde_yaml::to_writer(&mut stream, request)?;
+    stream.flush()?;
+    stream.shutdown(Shutdown::Write)?;
+    let reply: Reply = serde_yaml::from_reader(&stream)?;
+    Ok(reply?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tui::event::AppEvents;
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn stalled_client_does_not_block_others() {
+        let dir = std::env::temp_dir().join(format!("autoroute2-control-{}", std::process::id()));
+        let path = socket_path(&dir);
+        let events = AppEvents::with_sources(vec![listen(path.clone())]);
+        thread::spawn(move || {
+            while let Ok(event) = events.next() {
+                if let Event::Control(_, reply) = event {
+                    let _ = reply.send(Ok("120 BPM".to_string()));
+                }
+            }
+        });
+        while !path.exists() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // connects, then never finishes its request
+        let _stalled = UnixStream::connect(&path).unwrap();
+        let start = Instant::now();
+        assert_eq!(send(&dir, &Request::Tempo).unwrap(), "120 BPM");
+        assert!(start.elapsed() < REQUEST_TIMEOUT * 2);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,169 @@
+use crate::clock::InternalClock;
+use crate::control::{self, Reply, Request};
+use crate::forward::Forwarder;
+use crate::ledger::Ledger;
+use crate::ports::{AlsaMidi, Sub};
+use crate::prefs::UserPrefs;
+use crate::tui::event::{alsa_announce, vital, AppEvents, Event};
+use alsa::seq::Addr;
+use std::collections::{HashSet, VecDeque};
+use std::error::Error;
+use std::sync::mpsc::{RecvTimeoutError, Sender};
+use std::time::Duration;
+
+/// Plugging a device in produces a burst of client & port announces.
+/// Wait for things to settle before rewiring so the burst costs a single pass.
+const SETTLE_DELAY: Duration = Duration::from_millis(250);
+
+/// Apply routing once, then again every time the System Announce port reports
+/// a port appearing, disappearing or changing. Only returns if the announce
+/// monitor dies, with an error so that the supervisor (systemd) restarts us.
+pub fn run(prefs: &UserPrefs) -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::load(&prefs.state_dir())?;
+    // ports cut for flooding are excluded from then on
+    let mut prefs = prefs.clone();
+    let clock = prefs.prefs_model.internal_clock.as_ref().map(InternalClock::start);
+    let events = AppEvents::with_sources(vec![
+        vital(Box::new(alsa_announce)),
+        control::listen(control::socket_path(&prefs.state_dir())),
+    ]);
+    let forwarder = Forwarder::start(events.sender());
+
+    // subscriptions as of the last pass
+    let mut routed = HashSet::new();
+    // events that came in while settling
+    let mut pending = VecDeque::new();
+    apply(&prefs, &mut ledger, &forwarder, &mut routed);
+    loop {
+        let event = match pending.pop_front() {
+            Some(event) => event,
+            None => events.next()?,
+        };
+        match event {
+            Event::MidiPortsChanged(_) => {
+                settle(&events, &mut pending)?;
+                apply(&prefs, &mut ledger, &forwarder, &mut routed);
+            }
+            Event::Control(request, reply) => {
+                handle(&prefs, &forwarder, clock.as_ref(), request, reply)
+            }
+            Event::Runaway(sender, rate) => {
+                cut(&mut prefs, &mut ledger, sender, rate);
+                apply(&prefs, &mut ledger, &forwarder, &mut routed);
+            }
+            Event::SourceFailed(reason) => {
+                return Err(format!("Announce monitor stopped: {}", reason).into())
+            }
+            Event::KeyPressed(_) | Event::Tick => {}
+        }
+    }
+}
+
+/// Stop routing anything from a flooding port until the daemon restarts.
+/// Links go away with the next `apply`, subscriptions autoroute made right now.
+fn cut(prefs: &mut UserPrefs, ledger: &mut Ledger, sender: Addr, rate: u32) {
+    match AlsaMidi::new() {
+        Ok(midi) => {
+            if let Some(port) = midi.port(sender) {
+                eprintln!(
+                    "Cutting routes from {}: {} messages per second, feedback loop?",
+                    prefs.resolve_to_alias(port),
+                    rate
+                );
+                prefs.cut(port);
+                if let Err(err) = midi.cut(sender, ledger) {
+                    eprintln!("Could not cut routes from {}: {}", port.id(), err)
+                }
+            }
+        }
+        Err(err) => eprintln!("Could not read ALSA sequencer state: {}", err),
+    }
+}
+
+fn handle(
+    prefs: &UserPrefs,
+    forwarder: &Forwarder,
+    clock: Option<&InternalClock>,
+    request: Request,
+    reply: Sender<Reply>,
+) {
+    match request {
+        Request::Shift { from, semitones } => match AlsaMidi::new() {
+            Ok(midi) => {
+                let senders: Vec<Addr> =
+                    prefs.resolve_ports(&from, &midi.ports).iter().map(|p| p.addr).collect();
+                if senders.is_empty() {
+                    let _ = reply.send(Err(format!("No port named {}", from)));
+                } else {
+                    forwarder.shift(senders, semitones, reply)
+                }
+            }
+            Err(e) => {
+                let _ = reply.send(Err(e.to_string()));
+            }
+        },
+        Request::Tempo => forwarder.clock_status(reply),
+        Request::Links => forwarder.links(reply),
+        Request::Clock(transport) => match clock {
+            Some(clock) => clock.transport(transport, reply),
+            None => {
+                let _ = reply.send(Err("No internal-clock in config".to_string()));
+            }
+        },
+    }
+}
+
+/// Swallow port events until none have arrived for `SETTLE_DELAY`.
+/// Others are kept for later.
+fn settle(events: &AppEvents, pending: &mut VecDeque<Event>) -> Result<(), Box<dyn Error>> {
+    loop {
+        match events.next_timeout(SETTLE_DELAY) {
+            Ok(Event::MidiPortsChanged(_)) => continue,
+            Ok(event) => pending.push_back(event),
+            Err(RecvTimeoutError::Timeout) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Rebuild the port & subscription state from scratch and rewire.
+/// Failures are reported but never stop the daemon; the next announce retries.
+fn apply(prefs: &UserPrefs, ledger: &mut Ledger, forwarder: &Forwarder, routed: &mut HashSet<Sub>) {
+    match AlsaMidi::new() {
+        Ok(midi) => {
+            match midi.update_subs(prefs, ledger) {
+                Ok(report) => {
+                    for (change, err) in &report.failed {
+                        eprintln!("Failed {}: {}", midi.describe(prefs, change), err)
+                    }
+                }
+                Err(err) => eprintln!("Could not update routing: {}", err),
+            }
+            forwarder.set_links(midi.expected_links(prefs));
+            forwarder.set_clock_sources(midi.clock_sources(prefs), prefs.clock_timeout());
+        }
+        Err(err) => eprintln!("Could not read ALSA sequencer state: {}", err),
+    }
+
+    // whoever removed them, or unplugged the sender, subscriptions that went away
+    // may have left notes on at their destination
+    match AlsaMidi::new() {
+        Ok(midi) => {
+            let stuck: HashSet<Addr> = routed
+                .difference(&midi.subs)
+                .map(|sub| sub.dest)
+                .filter(|dest| midi.port(*dest).is_some())
+                .collect();
+            forwarder.panic(stuck.into_iter().collect());
+            let senders = midi.subs.iter().map(|sub| sub.sender);
+            let linked = midi.expected_links(prefs).into_iter().map(|link| link.sender);
+            let named = senders
+                .chain(linked)
+                .filter_map(|addr| Some((addr, prefs.resolve_to_alias(midi.port(addr)?))))
+                .collect();
+            forwarder.watch(named, prefs.rate_limit());
+            *routed = midi.subs;
+        }
+        Err(err) => eprintln!("Could not read ALSA sequencer state: {}", err),
+    }
+}
@@ -0,0 +1,397 @@
+use crate::clock::{ClockMeter, Failover};
+use crate::control::Reply;
+use crate::panic;
+use crate::ports::{new_port_sub, open_seq, CLIENT_NAME};
+use crate::prefs::{MessageKind, RoutePrefs, VelocityPrefs};
+use crate::process::{kind_of, Pipeline};
+use crate::tui::event::Event;
+use alsa::poll::{self, Descriptors};
+use alsa::seq::{Addr, EventType, PortCap, PortSubscribeIter, PortType, QuerySubsType, Seq};
+use alsa::Direction;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::ffi::CString;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to wait for MIDI input before checking for new instructions, in ms.
+const POLL_TIMEOUT: i32 = 50;
+
+/// A route that needs its messages processed, connecting two actual ports.
+/// Each link gets its own autoroute port, receiving from the sender and sending to the destination.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Link {
+    pub name: String,
+    pub sender: Addr,
+    pub dest: Addr,
+    pub route: RoutePrefs,
+    /// Velocity processing of the destination device
+    pub velocity: Option<VelocityPrefs>,
+}
+
+/// A link as a running daemon reports it.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct LinkState {
+    pub name: String,
+    /// ALSA client and port
+    pub sender: (i32, i32),
+    pub dest: (i32, i32),
+    pub route: RoutePrefs,
+    pub velocity: Option<VelocityPrefs>,
+}
+
+impl From<&Link> for LinkState {
+    fn from(link: &Link) -> Self {
+        LinkState {
+            name: link.name.clone(),
+            sender: (link.sender.client, link.sender.port),
+            dest: (link.dest.client, link.dest.port),
+            route: link.route.clone(),
+            velocity: link.velocity.clone(),
+        }
+    }
+}
+
+enum Command {
+    Links(Vec<Link>),
+    Shift { senders: Vec<Addr>, semitones: i32, reply: Sender<Reply> },
+    ClockSources { sources: Vec<(Addr, String)>, timeout: Duration },
+    ClockStatus(Sender<Reply>),
+    ListLinks(Sender<Reply>),
+    Panic(Vec<Addr>),
+    Watch { senders: HashMap<Addr, String>, limit: u32 },
+}
+
+/// Handle to the forwarding thread, which reads, processes and writes messages of every link.
+pub struct Forwarder {
+    tx: Sender<Command>,
+}
+
+impl Forwarder {
+    /// Runaway ports are reported on `events`.
+    pub fn start(events: Sender<Event>) -> Self {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            if let Err(e) = run(rx, events) {
+                eprintln!("MIDI forwarding stopped: {}", e);
+            }
+        });
+        Forwarder { tx }
+    }
+
+    /// Replace the current links. Links that didn't change keep their port and state.
+    pub fn set_links(&self, links: Vec<Link>) {
+        if self.tx.send(Command::Links(links)).is_err() {
+            eprintln!("MIDI forwarding is not running, processed routes are down")
+        }
+    }
+
+    /// Clock sources to choose from, preferred first, and how long they may go without ticking.
+    pub fn set_clock_sources(&self, sources: Vec<(Addr, String)>, timeout: Duration) {
+        if self.tx.send(Command::ClockSources { sources, timeout }).is_err() {
+            eprintln!("MIDI forwarding is not running, clock sources are down")
+        }
+    }
+
+    /// Silence these destinations, e.g. after they lost a subscription that may have left notes on.
+    pub fn panic(&self, dests: Vec<Addr>) {
+        if !dests.is_empty() && self.tx.send(Command::Panic(dests)).is_err() {
+            eprintln!("MIDI forwarding is not running, could not silence stuck notes")
+        }
+    }
+
+    /// Count messages from these senders, by name, reporting those sending more than `limit`
+    /// per second. Their clock is measured on the way.
+    pub fn watch(&self, senders: HashMap<Addr, String>, limit: u32) {
+        if self.tx.send(Command::Watch { senders, limit }).is_err() {
+            eprintln!("MIDI forwarding is not running, feedback loops will go unnoticed")
+        }
+    }
+
+    /// Report which port the clock comes from, at what tempo, on `reply`.
+    pub fn clock_status(&self, reply: Sender<Reply>) {
+        if let Err(e) = self.tx.send(Command::ClockStatus(reply)) {
+            if let Command::ClockStatus(reply) = e.0 {
+                let _ = reply.send(Err("MIDI forwarding is not running".to_string()));
+            }
+        }
+    }
+
+    /// Report the links being served, on `reply`.
+    pub fn links(&self, reply: Sender<Reply>) {
+        if let Err(e) = self.tx.send(Command::ListLinks(reply)) {
+            if let Command::ListLinks(reply) = e.0 {
+                let _ = reply.send(Err("MIDI forwarding is not running".to_string()));
+            }
+        }
+    }
+
+    /// Shift notes of transposable links from these senders, on top of their current shift.
+    /// The outcome is sent on `reply`.
+    pub fn shift(&self, senders: Vec<Addr>, semitones: i32, reply: Sender<Reply>) {
+        if let Err(e) = self.tx.send(Command::Shift { senders, semitones, reply }) {
+            if let Command::Shift { reply, .. } = e.0 {
+                let _ = reply.send(Err("MIDI forwarding is not running".to_string()));
+            }
+        }
+    }
+}
+
+fn run(rx: Receiver<Command>, events: Sender<Event>) -> Result<(), Box<dyn Error>> {
+    let seq = open_seq()?;
+    // for messages that need to go out a bit later, like swung clock ticks
+    let queue = seq.alloc_named_queue(&CString::new(CLIENT_NAME)?)?;
+    seq.control_queue(queue, EventType::Start, 0, None)?;
+    seq.drain_output()?;
+    let mut links: HashMap<i32, (Link, Pipeline)> = HashMap::new();
+    // runtime transposition, by sender
+    let mut shifts: HashMap<Addr, i32> = HashMap::new();
+    let mut failover = Failover::default();
+    let mut meter = ClockMeter::default();
+    let panic_port = panic::open_port(&seq)?;
+    let mut rates = RateWatch::open(&seq)?;
+    let mut fds = (&seq, Some(Direction::Capture)).get()?;
+    let mut input = seq.input();
+
+    loop {
+        loop {
+            match rx.try_recv() {
+                Ok(Command::Links(new_links)) => {
+                    update_links(&seq, &mut links, new_links, &shifts)
+                }
+                Ok(Command::Shift { senders, semitones, reply }) => {
+                    let _ = reply.send(shift(&mut links, &mut shifts, &senders, semitones));
+                }
+                Ok(Command::ClockSources { sources, timeout }) => {
+                    failover.set_sources(sources, timeout);
+                    meter.set_timeout(timeout);
+                }
+                Ok(Command::ClockStatus(reply)) => {
+                    let _ = reply.send(Ok(meter.status(Instant::now())));
+                }
+                Ok(Command::ListLinks(reply)) => {
+                    let states: Vec<LinkState> = links.values().map(|(l, _)| l.into()).collect();
+                    let _ = reply.send(serde_yaml::to_string(&states).map_err(|e| e.to_string()));
+                }
+                Ok(Command::Watch { senders, limit }) => {
+                    rates.watch(&seq, senders.keys().copied().collect(), limit);
+                    meter.set_names(senders);
+                }
+                Ok(Command::Panic(dests)) => {
+                    for dest in dests {
+                        if let Err(e) = panic::silence(&seq, panic_port, dest) {
+                            eprintln!("Could not silence {:?}: {}", dest, e)
+                        }
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Ok(()),
+            }
+        }
+
+        poll::poll(&mut fds, POLL_TIMEOUT)?;
+        while input.event_input_pending(true)? > 0 {
+            let event = input.event_input()?;
+            let port = event.get_dest().port;
+            if port == rates.port {
+                if event.get_type() == EventType::Clock {
+                    meter.tick(event.get_source(), Instant::now(), failover.following());
+                }
+                for (sender, rate) in rates.count(event.get_source()) {
+                    events.send(Event::Runaway(sender, rate))?;
+                }
+                continue;
+            }
+            if let Some((link, pipeline)) = links.get_mut(&port) {
+                if kind_of(event.get_type()) == Some(MessageKind::Clock)
+                    && !failover.admits(link.sender, event.get_type())
+                {
+                    continue;
+                }
+                for (mut out, delay) in pipeline.process(&event) {
+                    out.set_source(port);
+                    out.set_subs();
+                    if delay == Duration::ZERO {
+                        out.set_direct();
+                        seq.event_output_direct(&mut out)?;
+                    } else {
+                        out.schedule_real(queue, true, delay);
+                        seq.event_output(&mut out)?;
+                        seq.drain_output()?;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Counts messages per sender, a second at a time.
+struct RateWatch {
+    /// Subscribed to every watched sender
+    port: i32,
+    senders: HashSet<Addr>,
+    limit: u32,
+    counts: HashMap<Addr, u32>,
+    since: Instant,
+    /// Already reported, no need to repeat until they are cut
+    reported: HashSet<Addr>,
+}
+
+impl RateWatch {
+    fn open(seq: &Seq) -> Result<Self, Box<dyn Error>> {
+        let port = seq.create_simple_port(
+            &CString::new("Rate watch")?,
+            PortCap::WRITE,
+            PortType::MIDI_GENERIC | PortType::APPLICATION,
+        )?;
+        Ok(RateWatch {
+            port,
+            senders: HashSet::new(),
+            limit: u32::MAX,
+            counts: HashMap::new(),
+            since: Instant::now(),
+            reported: HashSet::new(),
+        })
+    }
+
+    fn watch(&mut self, seq: &Seq, senders: HashSet<Addr>, limit: u32) {
+        let own = match seq.client_id() {
+            Ok(client) => Addr { client, port: self.port },
+            Err(e) => return eprintln!("Could not watch message rates: {}", e),
+        };
+        for gone in self.senders.difference(&senders) {
+            // unplugged ports take their subscriptions with them
+            let _ = seq.unsubscribe_port(*gone, own);
+        }
+        for new in senders.difference(&self.senders) {
+            if let Err(e) = new_port_sub(*new, own).and_then(|s| seq.subscribe_port(&s)) {
+                eprintln!("Could not watch message rate of {:?}: {}", new, e)
+            }
+        }
+        self.reported.retain(|s| senders.contains(s));
+        self.senders = senders;
+        self.limit = limit;
+    }
+
+    /// Count one message. Returns senders over the limit once the second is up.
+    fn count(&mut self, sender: Addr) -> Vec<(Addr, u32)> {
+        *self.counts.entry(sender).or_insert(0) += 1;
+        let elapsed = self.since.elapsed();
+        if elapsed < Duration::from_secs(1) {
+            return vec![];
+        }
+        let mut runaways = vec![];
+        for (sender, count) in self.counts.drain() {
+            let rate = (count as f64 / elapsed.as_secs_f64()) as u32;
+            if rate > self.limit && self.reported.insert(sender) {
+                runaways.push((sender, rate));
+            }
+        }
+        self.since = Instant::now();
+        runaways
+    }
+}
+
+fn shift(
+    links: &mut HashMap<i32, (Link, Pipeline)>,
+    shifts: &mut HashMap<Addr, i32>,
+    senders: &[Addr],
+    semitones: i32,
+) -> Reply {
+    let mut shifted = vec![];
+    for sender in senders {
+        let total = shifts.entry(*sender).or_insert(0);
+        *total += semitones;
+        for (link, pipeline) in links.values_mut() {
+            if link.sender == *sender && link.route.is_transposable() {
+                pipeline.set_shift(*total);
+                shifted.push(format!("{} shifted {:+} semitones", link.name, total));
+            }
+        }
+    }
+    if shifted.is_empty() {
+        // nothing to apply it to, don't keep it around for later either
+        for sender in senders {
+            shifts.remove(sender);
+        }
+        return Err("No transposable route from there".to_string());
+    }
+    Ok(shifted.join(", "))
+}
+
+/// Drop the ports of links that went away, open ports for new ones.
+fn update_links(
+    seq: &Seq,
+    links: &mut HashMap<i32, (Link, Pipeline)>,
+    new_links: Vec<Link>,
+    shifts: &HashMap<Addr, i32>,
+) {
+    links.retain(|port, (link, pipeline)| {
+        let keep = new_links.contains(link);
+        if !keep {
+            // the destination would never hear the end of notes still held
+            for mut off in pipeline.release() {
+                if let Err(e) = panic::send_to(seq, *port, link.dest, &mut off) {
+                    eprintln!("Could not release notes of {}: {}", link.name, e);
+                    break;
+                }
+            }
+            // deleting the port also removes its subscriptions
+            if let Err(e) = seq.delete_port(*port) {
+                eprintln!("Could not close port for {}: {}", link.name, e)
+            }
+        }
+        keep
+    });
+
+    for link in new_links {
+        if let Some((port, _)) = links.iter().find(|(_, (l, _))| *l == link) {
+            // a device replugged quickly may come back with the same address, minus subscriptions
+            if let Err(e) = subscribe_link(seq, *port, &link) {
+                eprintln!("Could not route {}: {}", link.name, e)
+            }
+            continue;
+        }
+        match open_link(seq, &link) {
+            Ok(port) => {
+                let mut pipeline = Pipeline::new(link.route.clone(), link.velocity.clone());
+                pipeline.set_shift(shifts.get(&link.sender).copied().unwrap_or(0));
+                links.insert(port, (link, pipeline));
+            }
+            Err(e) => eprintln!("Could not route {}: {}", link.name, e),
+        }
+    }
+}
+
+fn open_link(seq: &Seq, link: &Link) -> Result<i32, Box<dyn Error>> {
+    // no SUBS_ caps, this port is not for anyone else to connect to
+    let port = seq.create_simple_port(
+        &CString::new(link.name.as_str())?,
+        PortCap::READ | PortCap::WRITE,
+        PortType::MIDI_GENERIC | PortType::APPLICATION,
+    )?;
+    if let Err(e) = subscribe_link(seq, port, link) {
+        seq.delete_port(port)?;
+        return Err(e);
+    }
+    Ok(port)
+}
+
+/// Connect the link's sender to our port and our port to its destination, unless already done.
+fn subscribe_link(seq: &Seq, port: i32, link: &Link) -> Result<(), Box<dyn Error>> {
+    let own = Addr { client: seq.client_id()?, port };
+    let subscribed = |sender: Addr, dest: Addr| {
+        PortSubscribeIter::new(seq, dest, QuerySubsType::WRITE)
+            .any(|s| s.get_sender() == sender && s.get_dest() == dest)
+    };
+    if !subscribed(link.sender, own) {
+        seq.subscribe_port(&new_port_sub(link.sender, own)?)?;
+    }
+    if !subscribed(own, link.dest) {
+        seq.subscribe_port(&new_port_sub(own, link.dest)?)?;
+    }
+    Ok(())
+}
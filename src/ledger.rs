@@ -0,0 +1,102 @@
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+const LEDGER_FILE: &str = "subscriptions.yaml";
+
+/// A subscription created by autoroute, identified by port names rather than
+/// ALSA addresses since client numbers change every time a device is replugged.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize, Clone)]
+pub struct OwnedSub {
+    pub sender: String,
+    pub dest: String,
+}
+
+/// Persisted record of the subscriptions autoroute created, so that routing
+/// passes only ever remove what autoroute put there in the first place.
+#[derive(Debug)]
+pub struct Ledger {
+    path: PathBuf,
+    subs: BTreeSet<OwnedSub>,
+}
+
+impl Ledger {
+    /// Load the ledger from `state_dir`, starting empty if none was saved yet.
+    pub fn load(state_dir: &Path) -> Result<Self, Box<dyn Error>> {
+        let path = state_dir.join(LEDGER_FILE);
+        let subs = match File::open(&path) {
+            Ok(file) => serde_yaml::from_reader(file)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeSet::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Ledger { path, subs })
+    }
+
+    /// Write the ledger next to its final location, then move it in place.
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = self.path.with_extension("yaml.tmp");
+        serde_yaml::to_writer(File::create(&tmp)?, &self.subs)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+
+    pub fn owns(&self, sender: &str, dest: &str) -> bool {
+        self.subs.contains(&OwnedSub { sender: sender.to_string(), dest: dest.to_string() })
+    }
+
+    pub fn insert(&mut self, sender: &str, dest: &str) {
+        self.subs.insert(OwnedSub { sender: sender.to_string(), dest: dest.to_string() });
+    }
+
+    pub fn remove(&mut self, sender: &str, dest: &str) {
+        self.subs.remove(&OwnedSub { sender: sender.to_string(), dest: dest.to_string() });
+    }
+
+    /// Forget entries for which `keep` returns false.
+    pub fn retain<F: FnMut(&OwnedSub) -> bool>(&mut self, keep: F) {
+        self.subs.retain(keep);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn starts_empty_then_persists() {
+        let dir = env::temp_dir().join(format!("autoroute2-ledger-{}", std::process::id()));
+        let mut ledger = Ledger::load(&dir).unwrap();
+        assert!(!ledger.owns("BeatStep", "RD-8"));
+
+        ledger.insert("BeatStep", "RD-8");
+        ledger.insert("BeatStep", "Neutron");
+        ledger.remove("BeatStep", "Neutron");
+        ledger.save().unwrap();
+
+        let ledger = Ledger::load(&dir).unwrap();
+        assert!(ledger.owns("BeatStep", "RD-8"));
+        assert!(!ledger.owns("BeatStep", "Neutron"));
+        // ownership goes one way
+        assert!(!ledger.owns("RD-8", "BeatStep"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn retains_what_is_kept() {
+        let dir = env::temp_dir().join(format!("autoroute2-retain-{}", std::process::id()));
+        let mut ledger = Ledger::load(&dir).unwrap();
+        ledger.insert("BeatStep", "RD-8");
+        ledger.insert("Neutron", "RD-8");
+        ledger.retain(|owned| owned.sender != "Neutron");
+        assert!(ledger.owns("BeatStep", "RD-8"));
+        assert!(!ledger.owns("Neutron", "RD-8"));
+    }
+}
@@ -1,38 +1,105 @@
 extern crate tui as tui_rs;
 
+use serde::Serialize;
 use structopt::StructOpt;
 
-use alsa::seq::{Addr, PortSubscribe};
-use alsa::{seq, Seq};
-
-use crate::tui::event::{AppEvents, Event};
+use crate::tui::event::AppEvents;
 use crate::tui::model::Model;
-use crate::tui::view;
-use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fs::File;
 use std::io;
-use std::io::Stdout;
-use std::time::Duration;
-use termion::event::Key;
+use std::path::Path;
+use std::str::FromStr;
 use termion::raw::IntoRawMode;
 use termion::screen::AlternateScreen;
 
-use crate::ports::AlsaMidi;
-use crate::prefs::{DevicePrefs, UserPrefs};
+use crate::control::{Request, Transport};
+use crate::forward::LinkState;
+use crate::ledger::Ledger;
+use crate::ports::{AlsaMidi, Connection};
+use crate::prefs::UserPrefs;
 use tui_rs::backend::TermionBackend;
 use tui_rs::Terminal;
 
+mod clock;
+mod control;
+mod daemon;
+mod forward;
+mod ledger;
+mod panic;
 mod ports;
 mod prefs;
+mod process;
 mod tui;
+mod usb;
+
+#[derive(Debug)]
+enum Format {
+    Text,
+    Json,
+    Yaml,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            "yaml" => Ok(Format::Yaml),
+            _ => Err(format!("{} is not one of text, json, yaml", s)),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Status {
+    connections: Vec<Connection>,
+    /// Clock followed by the daemon, if one is running
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clock: Option<String>,
+}
+
+/// Exit code of `plan` when the config and the current wiring differ.
+const PENDING_CHANGES: i32 = 2;
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "autoroute.py", about = "Automatically connect USB MIDI devices to each other")]
 enum CmdAction {
     Connect { config_file: String },
-    Ports { config_file: Option<String> },
-    TUI { config_file: Option<String> },
+    /// Show what `connect` would change, without changing it.
+    /// Exits with 2 if anything would change.
+    Plan { config_file: String },
+    /// Stay running and reapply routing every time MIDI ports come and go
+    Daemon { config_file: String },
+    /// List ports: as a config skeleton (yaml), or with their capabilities (text, json)
+    Ports {
+        #[structopt(long, default_value = "yaml")]
+        format: Format,
+        config_file: Option<String>,
+    },
+    /// Show current connections, and what a running daemon is up to
+    Status {
+        #[structopt(long, default_value = "text")]
+        format: Format,
+        config_file: Option<String>,
+    },
+    /// Silence every output: all notes off, all sound off and sustain off on every channel
+    Panic { config_file: Option<String> },
+    /// Start, stop or continue the internal clock of a running daemon
+    Clock { transport: Transport, config_file: String },
+    Tui { config_file: Option<String> },
+    /// Maintain the config file
+    Config(ConfigAction),
+}
+
+#[derive(StructOpt, Debug)]
+enum ConfigAction {
+    /// Add the ports the config doesn't know yet to its devices, in place.
+    /// The previous version is kept with a `.bak` suffix.
+    Merge { config_file: String },
 }
 
 // impl Default for CmdAction {
@@ -41,16 +108,28 @@ enum CmdAction {
 //     }
 // }
 
-fn add_ports(seq: &AlsaMidi, mut prefs: UserPrefs) -> Result<(), Box<dyn Error>> {
-    for p in &seq.ports {
-        if prefs.get_port_prefs(p.0.as_str()) == None {
-            prefs.prefs_model.devices.push(DevicePrefs::from_port(p.0.to_string()))
-        }
-    }
-    serde_yaml::to_writer(io::stdout(), &prefs.prefs_model)?;
+fn add_ports(seq: &AlsaMidi, prefs: &UserPrefs) -> Result<(), Box<dyn Error>> {
+    let mut model = prefs.prefs_model.clone();
+    model.devices.extend(prefs.new_devices(&seq.ports));
+    serde_yaml::to_writer(io::stdout(), &model)?;
     Ok(())
 }
 
+fn print_status(status: &Status) {
+    for c in &status.connections {
+        let marks = match (c.owned, c.expected) {
+            (true, true) => "autoroute, config",
+            (true, false) => "autoroute, not in config",
+            (false, true) => "config",
+            (false, false) => "unmanaged",
+        };
+        println!("{} > {} ({})", c.from, c.to, marks);
+    }
+    if let Some(clock) = &status.clock {
+        println!("Clock: {}", clock);
+    }
+}
+
 fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
     let cmd = CmdAction::from_args();
 
@@ -58,29 +137,146 @@ fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
         CmdAction::Connect { config_file } => {
             let file = File::open(config_file)?;
             let config = UserPrefs::load_from(&file)?;
+            let mut ledger = Ledger::load(&config.state_dir())?;
+            let ports = AlsaMidi::new()?;
+            let report = ports.update_subs(&config, &mut ledger)?;
+            for change in &report.done {
+                println!("{}", ports.describe(&config, change))
+            }
+            for (change, err) in &report.failed {
+                eprintln!("Failed {}: {}", ports.describe(&config, change), err)
+            }
+            let cut_off = report.cut_off();
+            if !cut_off.is_empty() {
+                panic::dests(&cut_off)?;
+            }
+            let links = ports.expected_links(&config);
+            if !links.is_empty() {
+                eprintln!("{} routes process messages and need to run as a daemon", links.len())
+            }
+            if !report.is_ok() {
+                let total = report.done.len() + report.failed.len();
+                return Err(format!("{} of {} changes failed", report.failed.len(), total).into());
+            }
+        }
+        CmdAction::Plan { config_file } => {
+            let file = File::open(config_file)?;
+            let config = UserPrefs::load_from(&file)?;
+            let ledger = Ledger::load(&config.state_dir())?;
+            let ports = AlsaMidi::new()?;
+            for warning in ports.warnings(&config) {
+                eprintln!("{}", warning)
+            }
+            let mut changes: Vec<String> =
+                ports.plan(&config, &ledger).iter().map(|c| ports.describe(&config, c)).collect();
+            // processed routes live in the daemon, if there is one
+            let running: Option<Vec<LinkState>> =
+                control::send(&config.state_dir(), &Request::Links)
+                    .ok()
+                    .and_then(|links| serde_yaml::from_str(&links).ok());
+            changes.extend(ports.describe_links(&config, running.as_deref()));
+            if changes.is_empty() {
+                println!("Nothing to change");
+                return Ok(());
+            }
+            changes.sort();
+            for change in changes {
+                println!("{}", change)
+            }
+            std::process::exit(PENDING_CHANGES);
+        }
+        CmdAction::Daemon { config_file } => {
+            let file = File::open(config_file)?;
+            let config = UserPrefs::load_from(&file)?;
+            daemon::run(&config)?;
+        }
+        CmdAction::Status { format, config_file } => {
+            let prefs = match config_file.map(File::open) {
+                Some(Err(e)) => return Err(e.into()),
+                Some(Ok(f)) => UserPrefs::load_from(&f)?,
+                None => UserPrefs::default(),
+            };
+            let ledger = Ledger::load(&prefs.state_dir())?;
             let ports = AlsaMidi::new()?;
-            ports.update_subs(&config)?;
+            let status = Status {
+                connections: ports.connections(&prefs, &ledger),
+                clock: control::send(&prefs.state_dir(), &Request::Tempo).ok(),
+            };
+            match format {
+                Format::Text => print_status(&status),
+                Format::Json => println!("{}", serde_json::to_string_pretty(&status)?),
+                Format::Yaml => serde_yaml::to_writer(io::stdout(), &status)?,
+            }
+        }
+        CmdAction::Panic { config_file } => {
+            let prefs = match config_file.map(File::open) {
+                Some(Err(e)) => return Err(e.into()),
+                Some(Ok(f)) => UserPrefs::load_from(&f)?,
+                None => UserPrefs::default(),
+            };
+            println!("Silenced {} ports", panic::all_outputs(&prefs)?);
         }
-        CmdAction::Ports { config_file } => {
+        CmdAction::Clock { transport, config_file } => {
+            let file = File::open(config_file)?;
+            let config = UserPrefs::load_from(&file)?;
+            println!("{}", control::send(&config.state_dir(), &Request::Clock(transport))?);
+        }
+        CmdAction::Ports { format, config_file } => {
             // if file is provided, it _must_ open successfully
-            let mut prefs = match config_file.map(|f| File::open(f)) {
+            let prefs = match config_file.map(File::open) {
                 Some(Err(e)) => return Err(e.into()),
                 Some(Ok(f)) => UserPrefs::load_from(&f)?,
                 None => UserPrefs::default(),
             };
 
             let ports = AlsaMidi::new()?;
-            add_ports(&ports, prefs)?;
+            match format {
+                Format::Yaml => add_ports(&ports, &prefs)?,
+                Format::Json => {
+                    println!("{}", serde_json::to_string_pretty(&ports.listing(&prefs))?)
+                }
+                Format::Text => {
+                    for p in ports.listing(&prefs) {
+                        println!(
+                            "{} {} [{}] [{}]{}",
+                            p.id,
+                            p.alias.as_deref().unwrap_or(&p.name),
+                            p.caps.join(" "),
+                            p.types.join(" "),
+                            if p.configured { "" } else { " (not in config)" }
+                        );
+                    }
+                }
+            }
+        }
+        CmdAction::Config(ConfigAction::Merge { config_file }) => {
+            let prefs = UserPrefs::load_from(File::open(&config_file)?)?;
+            let ports = AlsaMidi::new()?;
+            for device in prefs.unseen_devices(&ports.ports) {
+                eprintln!("Configured device {} was not seen", device.port_name);
+            }
+            let devices = prefs.new_devices(&ports.ports);
+            if devices.is_empty() {
+                println!("No new device");
+            } else {
+                for device in &devices {
+                    println!("+ {}", device.port_name);
+                }
+                prefs::merge_devices(Path::new(&config_file), &devices)?;
+            }
         }
-        CmdAction::TUI { config_file } => {
-            let mut prefs = match config_file.map(|f| File::open(f)) {
+        CmdAction::Tui { config_file } => {
+            let prefs = match config_file.map(File::open) {
                 Some(Err(e)) => return Err(e.into()),
                 Some(Ok(f)) => UserPrefs::load_from(&f)?,
                 None => UserPrefs::default(),
             };
 
-            let events =
-                AppEvents::with_sources(vec![tui::event::keyboard, tui::event::alsa_announce]);
+            let events = AppEvents::with_sources(vec![
+                Box::new(tui::event::keyboard),
+                Box::new(tui::event::alsa_announce),
+                Box::new(tui::event::ticker),
+            ]);
 
             let stdout = io::stdout().into_raw_mode()?;
             // let stdout = MouseTerminal::from(stdout);
@@ -90,7 +286,7 @@ fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
             terminal.hide_cursor()?;
 
             let ports = AlsaMidi::new()?;
-            let ports = ports.ports.keys().map(|k| k.to_string()).collect();
+            let ports = ports.ports.iter().map(|p| p.id()).collect();
             let mut app = Model::new("USB MIDI Routing", ports, prefs);
             app.run(events, terminal)?;
         }
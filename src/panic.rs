@@ -0,0 +1,66 @@
+use crate::ports::{open_seq, AlsaMidi};
+use crate::prefs::UserPrefs;
+use alsa::seq::{Addr, EvCtrl, Event, EventType, PortCap, PortType, Seq};
+use std::error::Error;
+use std::ffi::CString;
+
+/// Controllers that silence a channel: sustain off, all sound off, all notes off.
+const SILENCE: [(u32, i32); 3] = [(64, 0), (120, 0), (123, 0)];
+
+/// A port to send panic messages from.
+pub fn open_port(seq: &Seq) -> Result<i32, Box<dyn Error>> {
+    Ok(seq.create_simple_port(
+        &CString::new("Panic")?,
+        PortCap::READ,
+        PortType::MIDI_GENERIC | PortType::APPLICATION,
+    )?)
+}
+
+/// Release everything on every channel of the destination, held notes or not.
+pub fn silence(seq: &Seq, port: i32, dest: Addr) -> Result<(), alsa::Error> {
+    for channel in 0..16 {
+        for &(param, value) in SILENCE.iter() {
+            let mut event = Event::new(EventType::Controller, &EvCtrl { channel, param, value });
+            send_to(seq, port, dest, &mut event)?;
+        }
+    }
+    Ok(())
+}
+
+/// Send a single message straight to the destination, whatever is subscribed.
+pub fn send_to(seq: &Seq, port: i32, dest: Addr, event: &mut Event) -> Result<(), alsa::Error> {
+    event.set_source(port);
+    event.set_dest(dest);
+    event.set_direct();
+    seq.event_output_direct(event).map(|_| ())
+}
+
+/// Silence these destinations, e.g. after connections to them were removed.
+pub fn dests(dests: &[Addr]) -> Result<(), Box<dyn Error>> {
+    let seq = open_seq()?;
+    let port = open_port(&seq)?;
+    for dest in dests {
+        if let Err(e) = silence(&seq, port, *dest) {
+            eprintln!("Could not silence {:?}: {}", dest, e)
+        }
+    }
+    Ok(())
+}
+
+/// Silence every port that can play notes. Returns how many were hit.
+pub fn all_outputs(user: &UserPrefs) -> Result<usize, Box<dyn Error>> {
+    let midi = AlsaMidi::new()?;
+    let seq = open_seq()?;
+    let port = open_port(&seq)?;
+    let mut count = 0;
+    for dest in &midi.ports {
+        if user.is_ignored(dest) || !user.receives(dest) {
+            continue;
+        }
+        match silence(&seq, port, dest.addr) {
+            Ok(_) => count += 1,
+            Err(e) => eprintln!("Could not silence {}: {}", dest.id(), e),
+        }
+    }
+    Ok(count)
+}
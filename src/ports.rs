@@ -1,11 +1,16 @@
-use crate::prefs::{DevicePrefs, DeviceRole, PortDir, UserPrefs};
-use crate::tui::event::AppEvents;
-use alsa::seq::{Addr, PortSubscribe};
-use alsa::seq::{PortCap, PortType};
+use crate::forward::{Link, LinkState};
+use crate::ledger::Ledger;
+use crate::prefs::{DeviceRole, MessageKind, RoutePrefs, UserPrefs};
+use crate::usb::{self, UsbInfo};
+use alsa::seq::{Addr, PortCap, PortSubscribe, PortType};
 use alsa::{seq, Seq};
+use serde::Serialize;
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::ffi::CStr;
+use std::ffi::CString;
+use std::io::{self, ErrorKind};
+use std::thread;
+use std::time::Duration;
 
 // #[derive(Debug, PartialEq, Clone)]
 // struct ConnectedDevice {
@@ -13,38 +18,231 @@ use std::ffi::CStr;
 //     addr: Addr,
 // }
 
-#[derive(Debug, PartialEq, Hash, Eq)]
+#[derive(Debug, PartialEq, Hash, Eq, Clone, Copy)]
 pub struct Sub {
-    sender: Addr,
-    dest: Addr,
+    pub sender: Addr,
+    pub dest: Addr,
 }
 
+#[derive(Debug, PartialEq, Clone)]
+pub struct MidiPort {
+    pub name: String,
+    pub addr: Addr,
+    /// Position among ports sharing the same name, by ALSA client number
+    pub instance: usize,
+    pub usb: Option<UsbInfo>,
+    pub client_name: String,
+    pub caps: PortCap,
+    pub port_type: PortType,
+}
+
+impl MidiPort {
+    /// Port name, numbered if other ports share the same name.
+    pub fn id(&self) -> String {
+        match self.instance {
+            0 => self.name.clone(),
+            n => format!("{} #{}", self.name, n + 1),
+        }
+    }
+
+    /// ALSA client name and port index, e.g. `Neutron(1):0`.
+    pub fn client_port(&self) -> String {
+        format!("{}:{}", self.client_name, self.addr.port)
+    }
+
+    /// Other ports can subscribe to what this port sends.
+    pub fn can_send(&self) -> bool {
+        self.caps.contains(PortCap::READ | PortCap::SUBS_READ)
+    }
+
+    /// Other ports can subscribe this port to what they send.
+    pub fn can_receive(&self) -> bool {
+        self.caps.contains(PortCap::WRITE | PortCap::SUBS_WRITE)
+    }
+}
+
+/// A subscription to make or remove, and why.
+#[derive(Clone)]
+pub enum Change {
+    Subscribe(Sub, String),
+    Unsubscribe(Sub, String),
+}
+
+/// What came of each change a routing pass tried.
+#[derive(Default)]
+pub struct ApplyReport {
+    pub done: Vec<Change>,
+    pub failed: Vec<(Change, String)>,
+}
+
+impl ApplyReport {
+    pub fn is_ok(&self) -> bool {
+        self.failed.is_empty()
+    }
+
+    /// Destinations that lost a subscription, which may have left notes on.
+    pub fn cut_off(&self) -> Vec<Addr> {
+        let mut dests = vec![];
+        for change in &self.done {
+            if let Change::Unsubscribe(s, _) = change {
+                if !dests.contains(&s.dest) {
+                    dests.push(s.dest)
+                }
+            }
+        }
+        dests
+    }
+}
+
+/// How many times a subscription change is tried before giving up on it.
+const ATTEMPTS: u32 = 4;
+
+/// Wait before the first retry, doubled after every attempt.
+const BACKOFF: Duration = Duration::from_millis(20);
+
+fn error_kind(err: &alsa::Error) -> Option<ErrorKind> {
+    err.errno().map(|errno| io::Error::from_raw_os_error(errno as i32).kind())
+}
+
+/// Busy ports and interrupted calls may well succeed a moment later.
+fn is_transient(err: &alsa::Error) -> bool {
+    matches!(
+        error_kind(err),
+        Some(ErrorKind::ResourceBusy | ErrorKind::WouldBlock | ErrorKind::Interrupted)
+    )
+}
+
+/// True if `sender` is subscribed to by `dest` right now.
+fn is_subscribed(seq: &Seq, s: &Sub) -> bool {
+    seq::PortSubscribeIter::new(seq, s.dest, seq::QuerySubsType::WRITE)
+        .any(|live| live.get_sender() == s.sender)
+}
+
+/// Run `op` again with growing delays for as long as it fails with transient errors.
+fn with_retry(mut op: impl FnMut() -> Result<(), alsa::Error>) -> Result<(), alsa::Error> {
+    let mut delay = BACKOFF;
+    for _ in 1..ATTEMPTS {
+        match op() {
+            Err(e) if is_transient(&e) => {
+                thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+    op()
+}
+
+/// What `ports --format json` tells about a port.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PortListing {
+    pub id: String,
+    pub name: String,
+    pub client: i32,
+    pub port: i32,
+    pub client_name: String,
+    pub caps: Vec<&'static str>,
+    pub types: Vec<&'static str>,
+    /// Matches a `devices` entry
+    pub configured: bool,
+    pub alias: Option<String>,
+}
+
+const CAP_NAMES: [(PortCap, &str); 8] = [
+    (PortCap::READ, "READ"),
+    (PortCap::WRITE, "WRITE"),
+    (PortCap::SYNC_READ, "SYNC_READ"),
+    (PortCap::SYNC_WRITE, "SYNC_WRITE"),
+    (PortCap::DUPLEX, "DUPLEX"),
+    (PortCap::SUBS_READ, "SUBS_READ"),
+    (PortCap::SUBS_WRITE, "SUBS_WRITE"),
+    (PortCap::NO_EXPORT, "NO_EXPORT"),
+];
+
+const TYPE_NAMES: [(PortType, &str); 14] = [
+    (PortType::SPECIFIC, "SPECIFIC"),
+    (PortType::MIDI_GENERIC, "MIDI_GENERIC"),
+    (PortType::MIDI_GM, "MIDI_GM"),
+    (PortType::MIDI_GS, "MIDI_GS"),
+    (PortType::MIDI_XG, "MIDI_XG"),
+    (PortType::MIDI_MT32, "MIDI_MT32"),
+    (PortType::MIDI_GM2, "MIDI_GM2"),
+    (PortType::SYNTH, "SYNTH"),
+    (PortType::DIRECT_SAMPLE, "DIRECT_SAMPLE"),
+    (PortType::SAMPLE, "SAMPLE"),
+    (PortType::HARDWARE, "HARDWARE"),
+    (PortType::SOFTWARE, "SOFTWARE"),
+    (PortType::SYNTHESIZER, "SYNTHESIZER"),
+    (PortType::APPLICATION, "APPLICATION"),
+];
+
+/// A live connection between two ports, as shown by `status`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Connection {
+    pub from: String,
+    pub to: String,
+    /// Made by autoroute
+    pub owned: bool,
+    /// Called for by the config
+    pub expected: bool,
+}
+
+/// Ports and subscriptions of the sequencer, as they were when read.
 pub struct AlsaMidi {
-    seq: Seq,
-    pub ports: HashMap<String, Addr>,
+    /// Ordered by ALSA address
+    pub ports: Vec<MidiPort>,
     pub subs: HashSet<Sub>,
 }
 
 pub const SYSTEM_CLIENT: i32 = 0;
-pub const SYSTEM_TIMER_PORT: i32 = 0;
 pub const SYSTEM_ANNOUNCE_PORT: i32 = 1;
 
+/// ALSA client name used by every sequencer connection autoroute opens.
+/// Ports belonging to clients with this name are never routed.
+pub const CLIENT_NAME: &str = "autoroute2";
+
+/// Open a sequencer connection identifying itself as autoroute.
+pub fn open_seq() -> Result<Seq, alsa::Error> {
+    let seq = seq::Seq::open(None, None, false)?;
+    let name = CString::new(CLIENT_NAME).expect("client name");
+    seq.set_client_name(&name)?;
+    Ok(seq)
+}
+
 impl AlsaMidi {
     pub fn new() -> Result<Self, alsa::Error> {
-        let seq = seq::Seq::open(None, None, false)?;
+        let seq = open_seq()?;
 
         let mut subs: HashSet<Sub> = HashSet::new();
-        let mut ports = HashMap::new();
+        let mut ports: Vec<MidiPort> = vec![];
+
+        // our own ports (announce monitor, forwarders) are not devices
+        let own_clients: HashSet<i32> = seq::ClientIter::new(&seq)
+            .filter(|client| matches!(client.get_name(), Ok(CLIENT_NAME)))
+            .map(|client| client.get_client())
+            .collect();
 
         for client in seq::ClientIter::new(&seq) {
-            if client.get_client() == SYSTEM_CLIENT {
+            if client.get_client() == SYSTEM_CLIENT || own_clients.contains(&client.get_client()) {
                 continue;
             }
+            let usb = usb::for_client(client.get_client());
+            let client_name = client.get_name().unwrap_or_default().to_owned();
             for p in seq::PortIter::new(&seq, client.get_client()) {
                 // nameless device? ignored!
                 if let Ok(name) = p.get_name() {
-                    let name = name.to_owned();
-                    ports.insert(name, Addr { client: p.get_client(), port: p.get_port() });
+                    let instance = ports.iter().filter(|other| other.name == name).count();
+                    ports.push(MidiPort {
+                        name: name.to_owned(),
+                        addr: Addr { client: p.get_client(), port: p.get_port() },
+                        instance,
+                        usb: usb.clone(),
+                        client_name: client_name.clone(),
+                        caps: p.get_capability(),
+                        port_type: p.get_type(),
+                    });
                 }
 
                 for s in seq::PortSubscribeIter::new(
@@ -52,89 +250,575 @@ impl AlsaMidi {
                     seq::Addr { client: p.get_client(), port: p.get_port() },
                     seq::QuerySubsType::WRITE,
                 ) {
+                    // system announce & timer subscriptions are not routes,
+                    // and forwarded routes are managed separately
+                    let sender = s.get_sender().client;
+                    if sender == SYSTEM_CLIENT || own_clients.contains(&sender) {
+                        continue;
+                    }
                     subs.insert(Sub { sender: s.get_sender(), dest: s.get_dest() });
                 }
             }
         }
-        Ok(AlsaMidi { seq, ports, subs })
+        Ok(AlsaMidi { ports, subs })
     }
 }
 
 fn broadcast(
-    port: (&String, &Addr),
-    other: (&String, &Addr),
-    oconfig: Option<&DevicePrefs>,
-    subs: &mut HashSet<Sub>,
+    port: &MidiPort,
+    other: &MidiPort,
+    user: &UserPrefs,
+    subs: &mut HashMap<Sub, String>,
+    name: &str,
 ) {
     // don't broadcast to input devices
-    if let Some(oconfig) = oconfig {
-        if oconfig.port_dir == PortDir::Input {
-            return;
-        }
+    if !user.sends(port) || !user.receives(other) {
+        return;
     }
-    subs.insert(Sub { sender: *port.1, dest: *other.1 });
+    let sub = Sub { sender: port.addr, dest: other.addr };
+    subs.entry(sub).or_insert_with(|| format!("{} broadcasts", name));
 }
 
 fn monitor(
-    port: (&String, &Addr),
-    other: (&String, &Addr),
-    oconfig: Option<&DevicePrefs>,
-    subs: &mut HashSet<Sub>,
+    port: &MidiPort,
+    other: &MidiPort,
+    user: &UserPrefs,
+    subs: &mut HashMap<Sub, String>,
+    name: &str,
 ) {
-    if let Some(oconfig) = oconfig {
-        // don't monitor broadcasters or output-only devices
+    // don't monitor output-only devices
+    if !user.sends(other) || !user.receives(port) {
+        return;
+    }
+    if let Some(oconfig) = user.get_port_prefs(other) {
+        // don't monitor broadcasters or clock sources
         // TODO make this configurable?
-        if oconfig.roles.contains(&DeviceRole::Broadcast) || oconfig.port_dir == PortDir::Output {
+        if oconfig.roles.contains(&DeviceRole::Broadcast)
+            || oconfig.roles.contains(&DeviceRole::ClockSource)
+        {
             return;
         }
     }
-    subs.insert(Sub { dest: *port.1, sender: *other.1 });
+    let sub = Sub { dest: port.addr, sender: other.addr };
+    subs.entry(sub).or_insert_with(|| format!("{} monitors", name));
 }
 
 impl AlsaMidi {
-    pub fn watch(&self, event: &AppEvents) -> Result<(), Box<dyn Error>> {
-        self.seq.create_simple_port(
-            CStr::from_bytes_with_nul("".as_bytes())?,
-            PortCap::WRITE,
-            PortType::APPLICATION,
-        )?;
-        Ok(())
+    pub fn port(&self, addr: Addr) -> Option<&MidiPort> {
+        self.ports.iter().find(|p| p.addr == addr)
+    }
+
+    pub fn port_by_id(&self, id: &str) -> Option<&MidiPort> {
+        self.ports.iter().find(|p| p.id() == id)
     }
 
-    pub fn update_subs(&self, user: &UserPrefs) -> Result<(), alsa::Error> {
-        let mut expected_subs: HashSet<Sub> = HashSet::new();
+    fn port_id(&self, addr: Addr) -> Option<String> {
+        self.port(addr).map(MidiPort::id)
+    }
+
+    /// Subscriptions required by device roles and explicit routes.
+    pub fn expected_subs(&self, user: &UserPrefs) -> HashSet<Sub> {
+        self.expected_reasons(user).into_keys().collect()
+    }
+
+    /// Subscriptions required by device roles and explicit routes, with the role or route
+    /// calling for each.
+    fn expected_reasons(&self, user: &UserPrefs) -> HashMap<Sub, String> {
+        let mut expected_subs: HashMap<Sub, String> = HashMap::new();
         for port in &self.ports {
-            if let Some(pconfig) = user.get_port_prefs(port.0) {
+            if let Some(pconfig) = user.get_port_prefs(port) {
+                if pconfig.ignore {
+                    continue;
+                }
+                let name = user.resolve_to_alias(port);
                 for other in &self.ports {
-                    if other == port {
+                    if other == port || user.is_ignored(other) {
                         continue;
                     }
-                    let oconfig = user.get_port_prefs(other.0);
                     for role in &pconfig.roles {
                         match role {
+                            // clock sources broadcast through links, see `clock_links`
+                            DeviceRole::Broadcast
+                                if pconfig.roles.contains(&DeviceRole::ClockSource) => {}
                             DeviceRole::Broadcast => {
-                                broadcast(port, other, oconfig, &mut expected_subs)
+                                broadcast(port, other, user, &mut expected_subs, &name)
                             }
                             DeviceRole::Monitor => {
-                                monitor(port, other, oconfig, &mut expected_subs)
+                                monitor(port, other, user, &mut expected_subs, &name)
                             }
+                            DeviceRole::ClockSource => {}
                         }
                     }
                 }
             }
         }
 
-        for s in expected_subs.difference(&self.subs) {
-            let ps = new_port_sub(s.sender, s.dest)?;
-            self.seq.subscribe_port(&ps)?
+        for route in user.prefs_model.routes.iter().filter(|r| r.is_direct()) {
+            for (sender, dest) in self.route_ports(user, route) {
+                // velocity processing needs a link, even on plain routes
+                if user.velocity(dest).is_none() {
+                    let sub = Sub { sender: sender.addr, dest: dest.addr };
+                    expected_subs
+                        .entry(sub)
+                        .or_insert_with(|| format!("route {} > {}", route.from, route.to));
+                }
+            }
+        }
+
+        expected_subs.retain(|s, _| match (self.port(s.sender), self.port(s.dest)) {
+            (Some(sender), Some(dest)) => !user.is_excluded(sender, dest),
+            _ => true,
+        });
+
+        // a pair served by a route link gets its messages processed there,
+        // a plain subscription on the side would deliver them twice
+        let linked: HashSet<(Addr, Addr)> =
+            self.route_links(user).iter().map(|l| (l.sender, l.dest)).collect();
+        expected_subs.retain(|s, _| !linked.contains(&(s.sender, s.dest)));
+        expected_subs
+    }
+
+    /// Routes that process messages on their way and the clock of clock sources,
+    /// to be served by the `Forwarder`.
+    pub fn expected_links(&self, user: &UserPrefs) -> Vec<Link> {
+        let mut links = self.route_links(user);
+        links.extend(self.clock_links(user));
+        links
+    }
+
+    /// Routes that process messages on their way.
+    fn route_links(&self, user: &UserPrefs) -> Vec<Link> {
+        let mut links = vec![];
+        for route in &user.prefs_model.routes {
+            for (sender, dest) in self.route_ports(user, route) {
+                let velocity = user.velocity(dest).cloned();
+                if route.is_direct() && velocity.is_none() {
+                    continue;
+                }
+                links.push(Link {
+                    name: format!(
+                        "{} > {}",
+                        user.resolve_to_alias(sender),
+                        user.resolve_to_alias(dest)
+                    ),
+                    sender: sender.addr,
+                    dest: dest.addr,
+                    route: route.clone(),
+                    velocity,
+                });
+            }
+        }
+        links
+    }
+
+    /// Present clock sources, the preferred one first, as listed in `clock-sources`.
+    pub fn clock_sources(&self, user: &UserPrefs) -> Vec<(Addr, String)> {
+        let mut sources: Vec<&MidiPort> = self
+            .ports
+            .iter()
+            .filter(|p| match user.get_port_prefs(p) {
+                Some(pconf) => !pconf.ignore && pconf.roles.contains(&DeviceRole::ClockSource),
+                None => false,
+            })
+            .collect();
+        let listed = &user.prefs_model.clock_sources;
+        sources.sort_by_key(|p| {
+            listed
+                .iter()
+                .position(|name| user.resolve_ports(name, &self.ports).contains(p))
+                .unwrap_or(listed.len())
+        });
+        sources.into_iter().map(|p| (p.addr, user.resolve_to_alias(p))).collect()
+    }
+
+    /// Clock sources send their clock to every other device except other clock sources,
+    /// and everything else too if they also broadcast.
+    /// Going through links keeps their clock apart from the rest of their messages.
+    /// A route from a clock source replaces its clock link to that device.
+    fn clock_links(&self, user: &UserPrefs) -> Vec<Link> {
+        let routed: HashSet<(Addr, Addr)> = user
+            .prefs_model
+            .routes
+            .iter()
+            .flat_map(|route| self.route_ports(user, route))
+            .map(|(sender, dest)| (sender.addr, dest.addr))
+            .collect();
+        let mut links = vec![];
+        for port in &self.ports {
+            let pconfig = match user.get_port_prefs(port) {
+                Some(pconfig) if !pconfig.ignore => pconfig,
+                _ => continue,
+            };
+            if !pconfig.roles.contains(&DeviceRole::ClockSource) || !user.sends(port) {
+                continue;
+            }
+            let broadcasts = pconfig.roles.contains(&DeviceRole::Broadcast);
+            for other in &self.ports {
+                if other == port || user.is_ignored(other) || user.is_excluded(port, other) {
+                    continue;
+                }
+                if !user.receives(other) || routed.contains(&(port.addr, other.addr)) {
+                    continue;
+                }
+                let oconfig = user.get_port_prefs(other);
+                let other_source =
+                    oconfig.map(|o| o.roles.contains(&DeviceRole::ClockSource)).unwrap_or(false);
+                let mut route = RoutePrefs {
+                    from: user.resolve_to_alias(port),
+                    to: user.resolve_to_alias(other),
+                    ..RoutePrefs::default()
+                };
+                match (broadcasts, other_source) {
+                    (false, true) => continue,
+                    (false, false) => route.keep = vec![MessageKind::Clock],
+                    (true, true) => route.drop = vec![MessageKind::Clock],
+                    (true, false) => {}
+                }
+                links.push(Link {
+                    name: format!("{} > {}", route.from, route.to),
+                    sender: port.addr,
+                    dest: other.addr,
+                    route,
+                    velocity: None,
+                });
+            }
+        }
+        links
+    }
+
+    /// Pairs of present ports connected by a route, minus ignored ports, excluded pairs
+    /// and ports that can't send or receive.
+    fn route_ports(&self, user: &UserPrefs, route: &RoutePrefs) -> Vec<(&MidiPort, &MidiPort)> {
+        let mut pairs = vec![];
+        for sender in user.resolve_ports(&route.from, &self.ports) {
+            for dest in user.resolve_ports(&route.to, &self.ports) {
+                if sender == dest
+                    || user.is_ignored(sender)
+                    || user.is_ignored(dest)
+                    || user.is_excluded(sender, dest)
+                    || !user.sends(sender)
+                    || !user.receives(dest)
+                {
+                    continue;
+                }
+                pairs.push((sender, dest));
+            }
+        }
+        pairs
+    }
+
+    /// Subscribe everything the config expects and unsubscribe what it doesn't.
+    /// Only subscriptions recorded in the `ledger` are removed, unless the config is exclusive.
+    /// A change failing doesn't stop the others, the report tells which went through.
+    pub fn update_subs(
+        &self,
+        user: &UserPrefs,
+        ledger: &mut Ledger,
+    ) -> Result<ApplyReport, Box<dyn Error>> {
+        let expected_subs = self.expected_subs(user);
+        for warning in self.warnings(user) {
+            eprintln!("{}", warning)
+        }
+
+        let report = self.apply_subs(&open_seq()?, &self.plan(user, ledger), ledger);
+
+        // forget subscriptions that went away on their own, except for unplugged devices
+        ledger.retain(|owned| {
+            match (self.port_by_id(&owned.sender), self.port_by_id(&owned.dest)) {
+                (Some(sender), Some(dest)) => {
+                    let sub = Sub { sender: sender.addr, dest: dest.addr };
+                    self.subs.contains(&sub) || expected_subs.contains(&sub)
+                }
+                _ => true,
+            }
+        });
+        ledger.save()?;
+        Ok(report)
+    }
+
+    /// Ports the config can't tell apart, and routing loops it makes, before they are wired.
+    pub fn warnings(&self, user: &UserPrefs) -> Vec<String> {
+        let mut warnings = user.ambiguous_matches(&self.ports);
+        warnings.extend(self.loop_warnings(user));
+        warnings
+    }
+
+    /// Routing loops the config makes, before they are wired.
+    fn loop_warnings(&self, user: &UserPrefs) -> Vec<String> {
+        let subs = self.expected_subs(user);
+        let links = self.expected_links(user);
+        let edges = subs.iter().map(|s| (s.sender, s.dest));
+        let mut warnings = vec![];
+        for ports in self.loops(edges.chain(links.iter().map(|l| (l.sender, l.dest)))) {
+            let names: Vec<String> = ports.iter().map(|p| user.resolve_to_alias(p)).collect();
+            warnings.push(format!(
+                "Routing loop between {}, devices echoing input will flood",
+                names.join(", ")
+            ));
+        }
+        warnings
+    }
+
+    /// Groups of ports that can reach each other through the given connections.
+    fn loops(&self, edges: impl Iterator<Item = (Addr, Addr)>) -> Vec<Vec<&MidiPort>> {
+        let mut graph: HashMap<Addr, Vec<Addr>> = HashMap::new();
+        for (sender, dest) in edges {
+            graph.entry(sender).or_default().push(dest);
+        }
+        let mut tarjan = Tarjan::new(&graph);
+        for port in &self.ports {
+            if !tarjan.index.contains_key(&port.addr) {
+                tarjan.visit(port.addr);
+            }
+        }
+        tarjan
+            .components
+            .iter()
+            .filter(|c| c.len() > 1)
+            .map(|c| self.ports.iter().filter(|p| c.contains(&p.addr)).collect())
+            .collect()
+    }
+
+    /// Remove the subscriptions autoroute made from this port.
+    /// Connections made by hand are left alone, as always.
+    /// One failing doesn't stop the others, it is reported and stays in the ledger.
+    pub fn cut(&self, sender: Addr, ledger: &mut Ledger) -> Result<(), Box<dyn Error>> {
+        let seq = open_seq()?;
+        for s in self.subs.iter().filter(|s| s.sender == sender) {
+            if let (Some(sender), Some(dest)) = (self.port_id(s.sender), self.port_id(s.dest)) {
+                if !ledger.owns(&sender, &dest) {
+                    continue;
+                }
+                match with_retry(|| seq.unsubscribe_port(s.sender, s.dest)) {
+                    Ok(_) => ledger.remove(&sender, &dest),
+                    Err(err) => eprintln!("Could not cut {} > {}: {}", sender, dest, err),
+                }
+            }
+        }
+        ledger.save()
+    }
+
+    /// Every port, with its capabilities and how the config sees it.
+    pub fn listing(&self, user: &UserPrefs) -> Vec<PortListing> {
+        self.ports
+            .iter()
+            .map(|p| {
+                let pconfig = user.get_port_prefs(p);
+                PortListing {
+                    id: p.id(),
+                    name: p.name.clone(),
+                    client: p.addr.client,
+                    port: p.addr.port,
+                    client_name: p.client_name.clone(),
+                    caps: CAP_NAMES
+                        .iter()
+                        .filter(|(c, _)| p.caps.contains(*c))
+                        .map(|c| c.1)
+                        .collect(),
+                    types: TYPE_NAMES
+                        .iter()
+                        .filter(|(t, _)| p.port_type.contains(*t))
+                        .map(|t| t.1)
+                        .collect(),
+                    configured: pconfig.is_some(),
+                    alias: pconfig.and_then(|pconf| pconf.alias.clone()),
+                }
+            })
+            .collect()
+    }
+
+    /// Current subscriptions between known ports, ordered by alias.
+    pub fn connections(&self, user: &UserPrefs, ledger: &Ledger) -> Vec<Connection> {
+        let expected = self.expected_subs(user);
+        let mut connections: Vec<Connection> = self
+            .subs
+            .iter()
+            .filter_map(|s| {
+                let (sender, dest) = (self.port(s.sender)?, self.port(s.dest)?);
+                Some(Connection {
+                    from: user.resolve_to_alias(sender),
+                    to: user.resolve_to_alias(dest),
+                    owned: ledger.owns(&sender.id(), &dest.id()),
+                    expected: expected.contains(s),
+                })
+            })
+            .collect();
+        connections.sort_by(|a, b| (&a.from, &a.to).cmp(&(&b.from, &b.to)));
+        connections
+    }
+
+    /// What `update_subs` would subscribe and unsubscribe, in that order.
+    pub fn plan(&self, user: &UserPrefs, ledger: &Ledger) -> Vec<Change> {
+        let expected = self.expected_reasons(user);
+        let mut changes = vec![];
+        for (s, reason) in &expected {
+            if !self.subs.contains(s) {
+                changes.push(Change::Subscribe(*s, reason.clone()));
+            }
+        }
+
+        for s in self.subs.iter().filter(|s| !expected.contains_key(s)) {
+            let owned = match (self.port_id(s.sender), self.port_id(s.dest)) {
+                (Some(sender), Some(dest)) => ledger.owns(&sender, &dest),
+                _ => false,
+            };
+            if !owned && !user.prefs_model.exclusive {
+                // made by hand or by another app, leave it alone
+                continue;
+            }
+            let reason = match (self.port(s.sender), self.port(s.dest)) {
+                (Some(sender), Some(dest)) if user.is_excluded(sender, dest) => "excluded",
+                (Some(sender), Some(dest)) if user.is_ignored(sender) || user.is_ignored(dest) => {
+                    "ignored"
+                }
+                _ if !owned => "not in config, exclusive",
+                _ => "no longer in config",
+            };
+            changes.push(Change::Unsubscribe(*s, reason.to_string()));
+        }
+        changes
+    }
+
+    /// Links to add (`+`), change (`~`) or drop (`-`), compared to those of the `running` daemon,
+    /// described like `describe` does subscriptions. With no daemon, every link is to add.
+    pub fn describe_links(&self, user: &UserPrefs, running: Option<&[LinkState]>) -> Vec<String> {
+        let expected: Vec<LinkState> =
+            self.expected_links(user).iter().map(LinkState::from).collect();
+        let running = match running {
+            Some(running) => running,
+            None => {
+                let reason = |l: &LinkState| format!("route {} > {}", l.route.from, l.route.to);
+                return expected
+                    .iter()
+                    .map(|l| format!("+ {} ({}, needs the daemon)", l.name, reason(l)))
+                    .collect();
+            }
+        };
+        let same_ports = |a: &LinkState, b: &LinkState| (a.sender, a.dest) == (b.sender, b.dest);
+        let mut lines = vec![];
+        for link in &expected {
+            match running.iter().find(|r| same_ports(r, link)) {
+                None => lines.push(format!(
+                    "+ {} (route {} > {})",
+                    link.name, link.route.from, link.route.to
+                )),
+                Some(current) if current != link => {
+                    lines.push(format!("~ {} (processing changed)", link.name))
+                }
+                Some(_) => {}
+            }
+        }
+        for link in running.iter().filter(|r| !expected.iter().any(|l| same_ports(l, r))) {
+            lines.push(format!("- {} (no longer in config)", link.name))
+        }
+        lines
+    }
+
+    /// One line per change: `+` to subscribe, `-` to unsubscribe, with aliases and the reason.
+    pub fn describe(&self, user: &UserPrefs, change: &Change) -> String {
+        let name = |addr: Addr| match self.port(addr) {
+            Some(port) => user.resolve_to_alias(port),
+            None => format!("{}:{}", addr.client, addr.port),
+        };
+        match change {
+            Change::Subscribe(s, reason) => {
+                format!("+ {} > {} ({})", name(s.sender), name(s.dest), reason)
+            }
+            Change::Unsubscribe(s, reason) => {
+                format!("- {} > {} ({})", name(s.sender), name(s.dest), reason)
+            }
+        }
+    }
+
+    fn apply_subs(&self, seq: &Seq, changes: &[Change], ledger: &mut Ledger) -> ApplyReport {
+        let mut report = ApplyReport::default();
+        let subscribes = changes.iter().filter(|c| matches!(c, Change::Subscribe(..)));
+        let unsubscribes = changes.iter().filter(|c| matches!(c, Change::Unsubscribe(..)));
+        for change in subscribes.chain(unsubscribes) {
+            let (s, subscribe) = match change {
+                Change::Subscribe(s, _) => (s, true),
+                Change::Unsubscribe(s, _) => (s, false),
+            };
+            let result = if subscribe {
+                new_port_sub(s.sender, s.dest).and_then(|ps| {
+                    with_retry(|| match seq.subscribe_port(&ps) {
+                        // already there, made by someone else since we looked
+                        Err(e) if error_kind(&e) == Some(ErrorKind::ResourceBusy)
+                            && is_subscribed(seq, s) =>
+                        {
+                            Ok(())
+                        }
+                        result => result,
+                    })
+                })
+            } else {
+                with_retry(|| seq.unsubscribe_port(s.sender, s.dest))
+            };
+            if let Err(err) = result {
+                report.failed.push((change.clone(), err.to_string()));
+                continue;
+            }
+            if let (Some(sender), Some(dest)) = (self.port_id(s.sender), self.port_id(s.dest)) {
+                if subscribe {
+                    ledger.insert(&sender, &dest)
+                } else {
+                    ledger.remove(&sender, &dest)
+                }
+            }
+            report.done.push(change.clone())
+        }
+        report
+    }
+}
+
+/// Strongly connected components, by Tarjan's algorithm.
+struct Tarjan<'a> {
+    graph: &'a HashMap<Addr, Vec<Addr>>,
+    index: HashMap<Addr, usize>,
+    lowlink: HashMap<Addr, usize>,
+    stack: Vec<Addr>,
+    components: Vec<Vec<Addr>>,
+}
+
+impl<'a> Tarjan<'a> {
+    fn new(graph: &'a HashMap<Addr, Vec<Addr>>) -> Self {
+        Tarjan {
+            graph,
+            index: HashMap::new(),
+            lowlink: HashMap::new(),
+            stack: vec![],
+            components: vec![],
+        }
+    }
+
+    fn visit(&mut self, node: Addr) {
+        let index = self.index.len();
+        self.index.insert(node, index);
+        self.lowlink.insert(node, index);
+        self.stack.push(node);
+
+        let graph = self.graph;
+        for next in graph.get(&node).into_iter().flatten() {
+            if !self.index.contains_key(next) {
+                self.visit(*next);
+                let low = self.lowlink[&node].min(self.lowlink[next]);
+                self.lowlink.insert(node, low);
+            } else if self.stack.contains(next) {
+                let low = self.lowlink[&node].min(self.index[next]);
+                self.lowlink.insert(node, low);
+            }
         }
 
-        for s in self.subs.difference(&expected_subs) {
-            if let Err(err) = self.seq.unsubscribe_port(s.sender, s.dest) {
-                eprintln!("Could not unsubscribe {:?} from {:?}: {}", s.sender, s.dest, err)
+        if self.lowlink[&node] == index {
+            let mut component = vec![];
+            while let Some(member) = self.stack.pop() {
+                component.push(member);
+                if member == node {
+                    break;
+                }
             }
+            self.components.push(component);
         }
-        Ok(())
     }
 }
 
@@ -144,3 +828,310 @@ pub fn new_port_sub(sender: Addr, dest: Addr) -> Result<PortSubscribe, alsa::Err
     ps.set_dest(dest);
     Ok(ps)
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// A port to build test setups with, first of its name.
+    pub fn port(client: i32, name: &str) -> MidiPort {
+        MidiPort {
+            name: name.to_owned(),
+            addr: Addr { client, port: 0 },
+            instance: 0,
+            usb: None,
+            client_name: name.to_owned(),
+            caps: PortCap::READ | PortCap::SUBS_READ | PortCap::WRITE | PortCap::SUBS_WRITE,
+            port_type: PortType::MIDI_GENERIC | PortType::HARDWARE,
+        }
+    }
+
+    /// Ports with these names from client 20 on, subscribed as given.
+    fn midi(names: &[&str], subs: &[(&str, &str)]) -> AlsaMidi {
+        let ports: Vec<MidiPort> =
+            names.iter().enumerate().map(|(i, n)| port(20 + i as i32, n)).collect();
+        let addr = |name: &str| ports.iter().find(|p| p.name == name).unwrap().addr;
+        let subs =
+            subs.iter().map(|(from, to)| Sub { sender: addr(from), dest: addr(to) }).collect();
+        AlsaMidi { ports, subs }
+    }
+
+    fn prefs(yaml: &str) -> UserPrefs {
+        UserPrefs::load_from(yaml.as_bytes()).unwrap()
+    }
+
+    fn sub(midi: &AlsaMidi, from: &str, to: &str) -> Sub {
+        let addr = |name: &str| midi.ports.iter().find(|p| p.name == name).unwrap().addr;
+        Sub { sender: addr(from), dest: addr(to) }
+    }
+
+    fn ledger(owned: &[(&str, &str)]) -> Ledger {
+        let dir = std::env::temp_dir().join(format!("autoroute2-plan-{}", std::process::id()));
+        let mut ledger = Ledger::load(&dir).unwrap();
+        for (sender, dest) in owned {
+            ledger.insert(sender, dest)
+        }
+        ledger
+    }
+
+    /// The plan as `+ from > to` and `- from > to` lines, in plan order.
+    fn plan(midi: &AlsaMidi, user: &UserPrefs, ledger: &Ledger) -> Vec<String> {
+        let name = |addr| midi.port(addr).unwrap().name.clone();
+        let line = |change: &Change| match change {
+            Change::Subscribe(s, _) => format!("+ {} > {}", name(s.sender), name(s.dest)),
+            Change::Unsubscribe(s, _) => format!("- {} > {}", name(s.sender), name(s.dest)),
+        };
+        let mut lines: Vec<String> = midi.plan(user, ledger).iter().map(line).collect();
+        // unsubscribes follow subscribes, each in hash order
+        lines.sort_by_key(|l| (l.starts_with('-'), l.clone()));
+        lines
+    }
+
+    const ROUTE: &str = "devices: []
+routes:
+  - from: BeatStep
+    to: RD-8
+";
+
+    #[test]
+    fn plan_removes_only_owned_subs() {
+        let midi = midi(
+            &["BeatStep", "RD-8", "Neutron"],
+            &[("BeatStep", "Neutron"), ("Neutron", "RD-8")],
+        );
+        let ledger = ledger(&[("BeatStep", "Neutron")]);
+        assert_eq!(
+            plan(&midi, &prefs(ROUTE), &ledger),
+            ["+ BeatStep > RD-8", "- BeatStep > Neutron"]
+        );
+    }
+
+    #[test]
+    fn plan_exclusive_removes_all_unexpected_subs() {
+        let midi = midi(
+            &["BeatStep", "RD-8", "Neutron"],
+            &[("BeatStep", "RD-8"), ("BeatStep", "Neutron"), ("Neutron", "RD-8")],
+        );
+        let user = prefs(&format!("exclusive: true\n{}", ROUTE));
+        assert_eq!(
+            plan(&midi, &user, &ledger(&[])),
+            ["- BeatStep > Neutron", "- Neutron > RD-8"]
+        );
+    }
+
+    #[test]
+    fn listing() {
+        let mut midi = midi(&["BeatStep", "RD-8"], &[]);
+        midi.ports[1].caps = PortCap::WRITE | PortCap::SUBS_WRITE;
+        let user = prefs(
+            "devices:
+  - port-name: RD-8
+    alias: drums
+",
+        );
+        let listing = midi.listing(&user);
+        assert_eq!((listing[0].client, listing[0].port), (20, 0));
+        assert_eq!(listing[0].client_name, "BeatStep");
+        assert_eq!(listing[0].caps, ["READ", "WRITE", "SUBS_READ", "SUBS_WRITE"]);
+        assert_eq!(listing[0].types, ["MIDI_GENERIC", "HARDWARE"]);
+        assert_eq!((listing[0].configured, listing[0].alias.as_deref()), (false, None));
+        assert_eq!(listing[1].caps, ["WRITE", "SUBS_WRITE"]);
+        assert_eq!((listing[1].configured, listing[1].alias.as_deref()), (true, Some("drums")));
+    }
+
+    #[test]
+    fn processed_route_replaces_broadcast() {
+        let midi = midi(&["Pyramid", "Sub37", "RD-8"], &[]);
+        let user = prefs(
+            "devices:
+  - port-name: Pyramid
+    roles: [Broadcast]
+routes:
+  - from: Pyramid
+    to: Sub37
+    drop: [Clock, Sysex]
+",
+        );
+        let subs = midi.expected_subs(&user);
+        assert!(!subs.contains(&sub(&midi, "Pyramid", "Sub37")));
+        assert!(subs.contains(&sub(&midi, "Pyramid", "RD-8")));
+        let links = midi.expected_links(&user);
+        assert_eq!(links.len(), 1);
+        assert_eq!((links[0].sender, links[0].dest), (midi.ports[0].addr, midi.ports[1].addr));
+    }
+
+    #[test]
+    fn processed_route_replaces_monitor() {
+        let midi = midi(&["BeatStep", "RD-8"], &[]);
+        let user = prefs(
+            "devices:
+  - port-name: RD-8
+    roles: [Monitor]
+routes:
+  - from: BeatStep
+    to: RD-8
+    channels: [1]
+",
+        );
+        assert!(midi.expected_subs(&user).is_empty());
+        assert_eq!(midi.expected_links(&user).len(), 1);
+    }
+
+    #[test]
+    fn velocity_dest_is_only_linked() {
+        let midi = midi(&["BeatStep", "Sub37"], &[]);
+        let user = prefs(
+            "devices:
+  - port-name: BeatStep
+    roles: [Broadcast]
+  - port-name: Sub37
+    velocity:
+      fixed: 100
+routes:
+  - from: BeatStep
+    to: Sub37
+",
+        );
+        assert!(midi.expected_subs(&user).is_empty());
+        assert_eq!(midi.expected_links(&user).len(), 1);
+    }
+
+    #[test]
+    fn plan_compares_links_with_daemon() {
+        let midi = midi(&["Pyramid", "Sub37", "RD-8"], &[]);
+        let before = prefs(
+            "devices: []
+routes:
+  - from: Pyramid
+    to: Sub37
+    drop: [Clock]
+  - from: Pyramid
+    to: RD-8
+    channels: [10]
+",
+        );
+        let running: Vec<LinkState> =
+            midi.expected_links(&before).iter().map(LinkState::from).collect();
+        assert!(midi.describe_links(&before, Some(&running)).is_empty());
+        // as the daemon sends them
+        let sent: Vec<LinkState> =
+            serde_yaml::from_str(&serde_yaml::to_string(&running).unwrap()).unwrap();
+        assert_eq!(sent, running);
+
+        let after = prefs(
+            "devices: []
+routes:
+  - from: Pyramid
+    to: Sub37
+    drop: [Clock, Sysex]
+  - from: Sub37
+    to: RD-8
+    transpose: 12
+",
+        );
+        let mut lines = midi.describe_links(&after, Some(&running));
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec![
+                "+ Sub37 > RD-8 (route Sub37 > RD-8)",
+                "- Pyramid > RD-8 (no longer in config)",
+                "~ Pyramid > Sub37 (processing changed)",
+            ]
+        );
+        assert_eq!(
+            midi.describe_links(&after, None),
+            vec![
+                "+ Pyramid > Sub37 (route Pyramid > Sub37, needs the daemon)",
+                "+ Sub37 > RD-8 (route Sub37 > RD-8, needs the daemon)",
+            ]
+        );
+    }
+
+    #[test]
+    fn report_lists_cut_off_dests_once() {
+        let midi = midi(&["A", "B", "C"], &[]);
+        let (ab, cb, ac) = (sub(&midi, "A", "B"), sub(&midi, "C", "B"), sub(&midi, "A", "C"));
+        let report = ApplyReport {
+            done: vec![
+                Change::Unsubscribe(ab, String::new()),
+                Change::Unsubscribe(cb, String::new()),
+                Change::Subscribe(ac, String::new()),
+            ],
+            failed: vec![(Change::Unsubscribe(ac, String::new()), "busy".to_string())],
+        };
+        assert_eq!(report.cut_off(), vec![ab.dest]);
+    }
+
+    #[test]
+    fn transient_errors() {
+        // Linux errno values
+        let (eintr, eagain, enomem, ebusy, einval) = (4, 11, 12, 16, 22);
+        let error = |errno| alsa::Error::new("snd_seq_subscribe_port", errno);
+        assert!(is_transient(&error(ebusy)));
+        assert!(is_transient(&error(eagain)));
+        assert!(is_transient(&error(eintr)));
+        assert!(!is_transient(&error(enomem)));
+        assert!(!is_transient(&error(einval)));
+    }
+
+    #[test]
+    fn plain_route_stays_subscribed() {
+        let midi = midi(&["Pyramid", "Sub37"], &[]);
+        let user = prefs(
+            "devices:
+  - port-name: Pyramid
+    roles: [Broadcast]
+routes:
+  - from: Pyramid
+    to: Sub37
+",
+        );
+        assert!(midi.expected_subs(&user).contains(&sub(&midi, "Pyramid", "Sub37")));
+        assert!(midi.expected_links(&user).is_empty());
+    }
+
+    #[test]
+    fn plain_route_from_clock_source_stays_subscribed() {
+        let midi = midi(&["Pyramid", "Sub37", "RD-8"], &[]);
+        let user = prefs(
+            "devices:
+  - port-name: Pyramid
+    roles: [ClockSource]
+routes:
+  - from: Pyramid
+    to: Sub37
+",
+        );
+        assert!(midi.expected_subs(&user).contains(&sub(&midi, "Pyramid", "Sub37")));
+        // the other device still gets the clock alone
+        let links = midi.expected_links(&user);
+        assert_eq!(links.len(), 1);
+        assert_eq!(links[0].dest, sub(&midi, "Pyramid", "RD-8").dest);
+        assert_eq!(links[0].route.keep, vec![MessageKind::Clock]);
+    }
+
+    #[test]
+    fn processed_route_from_clock_source_replaces_clock_link() {
+        for roles in ["[ClockSource, Broadcast]", "[ClockSource]"] {
+            let midi = midi(&["Pyramid", "Sub37"], &[]);
+            let user = prefs(&format!(
+                "devices:
+  - port-name: Pyramid
+    roles: {}
+routes:
+  - from: Pyramid
+    to: Sub37
+    drop: [Sysex]
+",
+                roles
+            ));
+            assert!(midi.expected_subs(&user).is_empty(), "{}", roles);
+            // one link carries everything, the clock included, so nothing arrives twice
+            let links = midi.expected_links(&user);
+            assert_eq!(links.len(), 1, "{}", roles);
+            assert_eq!(links[0].route.drop, vec![MessageKind::Sysex]);
+            assert!(links[0].route.keep.is_empty());
+        }
+    }
+}
@@ -1,10 +1,18 @@
-use alsa::seq::Addr;
-use std::collections::HashMap;
+use crate::ports::MidiPort;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::env;
 use std::error::Error;
-use std::fs::File;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File};
 use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+use regex::Regex;
 use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
 
 #[derive(Debug, PartialEq, Deserialize, Serialize, Clone)]
 pub enum PortDir {
@@ -13,73 +21,1291 @@ pub enum PortDir {
     Output,
 }
 
-impl Default for PortDir {
-    fn default() -> Self {
-        PortDir::Duplex
-    }
-}
-
 #[derive(Debug, PartialEq, Deserialize, Serialize, Clone)]
 pub enum DeviceRole {
     Broadcast,
     Monitor,
+    /// Sends its clock (and only its clock) to every other device, except other clock sources
+    ClockSource,
 }
 
-#[serde(rename_all = "kebab-case")]
 #[derive(Debug, PartialEq, Deserialize, Serialize, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
 pub struct DevicePrefs {
     pub port_name: String,
-    pub port_dir: PortDir,
+    /// Restrict a port to one direction, whatever ALSA says it can do
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port_dir: Option<PortDir>,
     pub alias: Option<String>,
     #[serde(default)]
     pub roles: Vec<DeviceRole>,
+    /// Never route to or from this port
+    #[serde(default)]
+    pub ignore: bool,
+    /// Tell apart devices sharing the same port name by the USB port they are plugged in,
+    /// e.g. `1-1.2`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usb_path: Option<String>,
+    /// ...or by their USB serial number, for devices that have one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usb_serial: Option<String>,
+    /// ...or by order of appearance, 0 being the lowest ALSA client number
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instance: Option<usize>,
+    /// Rework the velocity of notes routed to this port
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub velocity: Option<VelocityPrefs>,
 }
 
 impl DevicePrefs {
-    pub fn from_port(port_name: String) -> Self {
-        let mut dev = DevicePrefs::default();
-        dev.port_name = port_name;
-        dev
+    /// Defaults for a newly seen port. Ports nothing can subscribe to or from are ignored.
+    pub fn from_port(port: &MidiPort) -> Self {
+        let ignore = !port.can_send() && !port.can_receive();
+        DevicePrefs { port_name: port.name.clone(), ignore, ..DevicePrefs::default() }
+    }
+
+    /// True if the `port-name` is the port's name, or its client name and port index.
+    fn names(&self, port: &MidiPort) -> bool {
+        self.port_name == port.name || self.port_name == port.client_port()
+    }
+
+    /// A `port-name` between slashes is a regex.
+    fn regex(&self) -> Option<&str> {
+        let name = self.port_name.as_str();
+        name.strip_prefix('/').and_then(|n| n.strip_suffix('/'))
+    }
+
+    /// A `port-name` with `*` or `?` is a glob.
+    fn is_glob(&self) -> bool {
+        self.regex().is_none() && self.port_name.contains(&['*', '?'][..])
+    }
+
+    fn has_selectors(&self) -> bool {
+        self.usb_path.is_some() || self.usb_serial.is_some() || self.instance.is_some()
     }
+
+    /// True if every selector set on these prefs matches the port.
+    fn selects(&self, port: &MidiPort) -> bool {
+        let usb = port.usb.as_ref();
+        self.instance.map(|i| i == port.instance).unwrap_or(true)
+            && self.usb_path.as_ref().map(|p| usb.map(|u| &u.path) == Some(p)).unwrap_or(true)
+            && self
+                .usb_serial
+                .as_ref()
+                .map(|s| usb.and_then(|u| u.serial.as_ref()) == Some(s))
+                .unwrap_or(true)
+    }
+}
+
+/// Kinds of MIDI messages a route can let through or hold back.
+#[derive(Debug, PartialEq, Eq, Hash, Deserialize, Serialize, Clone, Copy)]
+pub enum MessageKind {
+    Note,
+    /// Polyphonic and channel pressure
+    Aftertouch,
+    /// Control changes, including 14 bit controllers and N/RPN
+    Controller,
+    ProgramChange,
+    PitchBend,
+    Sysex,
+    /// Clock ticks, start, stop, continue and song position
+    Clock,
+    /// Active sensing
+    Sensing,
 }
 
+/// Velocity processing for notes sent to a device. Steps apply in order:
+/// `curve`, then `scale` and `offset`, then the `min` / `max` clamp.
+/// A `fixed` velocity overrides all of them.
 #[derive(Debug, PartialEq, Deserialize, Serialize, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct VelocityPrefs {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fixed: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub curve: Option<VelocityCurve>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scale: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<i16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<u8>,
+}
+
+/// How strongly the exponential and logarithmic curves bend.
+const CURVE_BEND: f32 = 4.0;
+
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub enum VelocityCurve {
+    /// Soft hits get softer, for devices that sound too loud too early
+    Exponential,
+    /// Soft hits get louder, for devices that need hard hits to wake up
+    Logarithmic,
+    /// Outgoing velocity for each incoming velocity, 128 entries
+    Table(Vec<u8>),
+}
+
+impl VelocityPrefs {
+    /// Outgoing velocity of a note on. Never 0, which would turn it into a note off.
+    pub fn apply(&self, velocity: u8) -> u8 {
+        if let Some(fixed) = self.fixed {
+            return fixed;
+        }
+        let x = velocity as f32 / 127.0;
+        let curved = match &self.curve {
+            Some(VelocityCurve::Exponential) => {
+                127.0 * (CURVE_BEND * x).exp_m1() / CURVE_BEND.exp_m1()
+            }
+            Some(VelocityCurve::Logarithmic) => {
+                127.0 * (x * CURVE_BEND.exp_m1()).ln_1p() / CURVE_BEND
+            }
+            Some(VelocityCurve::Table(table)) => table[velocity as usize & 0x7f] as f32,
+            None => velocity as f32,
+        };
+        let scaled = curved * self.scale.unwrap_or(1.0) + self.offset.unwrap_or(0) as f32;
+        // validated to stay within 1..127
+        let (min, max) = (self.min.unwrap_or(1) as f32, self.max.unwrap_or(127) as f32);
+        scaled.round().clamp(min, max) as u8
+    }
+
+    fn validate(&self, port_name: &str) -> Result<(), String> {
+        let bad = |what: &str| Err(format!("Device {}: {}", port_name, what));
+        if let Some(VelocityCurve::Table(table)) = &self.curve {
+            if table.len() != 128 {
+                return bad(&format!("velocity table has {} entries, not 128", table.len()));
+            }
+            if table.iter().any(|v| *v > 127) {
+                return bad("velocity table entries go from 0 to 127");
+            }
+        }
+        let limits = self.fixed.iter().chain(&self.min).chain(&self.max);
+        if let Some(v) = limits.clone().find(|v| !(1..=127).contains(*v)) {
+            return bad(&format!("velocity {} out of 1..127", v));
+        }
+        if self.min.unwrap_or(1) > self.max.unwrap_or(127) {
+            return bad("velocity min is above max");
+        }
+        Ok(())
+    }
+}
+
+const NOTE_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+/// Inclusive range of MIDI notes, written `C-1..B2` (C-1 being note 0) or `0..47`.
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, Copy)]
+#[serde(try_from = "String", into = "String")]
+pub struct NoteRange {
+    pub low: u8,
+    pub high: u8,
+}
+
+impl NoteRange {
+    pub fn contains(&self, note: u8) -> bool {
+        (self.low..=self.high).contains(&note)
+    }
+}
+
+impl TryFrom<String> for NoteRange {
+    type Error = String;
+
+    fn try_from(range: String) -> Result<Self, Self::Error> {
+        let mut ends = range.splitn(2, "..");
+        let low = parse_note(ends.next().unwrap_or_default())?;
+        let high = match ends.next() {
+            Some(high) => parse_note(high)?,
+            None => low,
+        };
+        if low > high {
+            return Err(format!("Empty note range {}", range));
+        }
+        Ok(NoteRange { low, high })
+    }
+}
+
+impl From<NoteRange> for String {
+    fn from(range: NoteRange) -> Self {
+        range.to_string()
+    }
+}
+
+impl fmt::Display for NoteRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}..{}", note_name(self.low), note_name(self.high))
+    }
+}
+
+/// Parse a note number or name with sharp/flat & octave, e.g. `60`, `C4`, `Eb-1`
+fn parse_note(name: &str) -> Result<u8, String> {
+    let name = name.trim();
+    if let Ok(number) = name.parse::<u8>() {
+        return if number < 128 { Ok(number) } else { Err(format!("No such note {}", name)) };
+    }
+    let mut chars = name.chars();
+    let mut pitch = match chars.next().map(|c| c.to_ascii_uppercase()) {
+        Some('C') => 0,
+        Some('D') => 2,
+        Some('E') => 4,
+        Some('F') => 5,
+        Some('G') => 7,
+        Some('A') => 9,
+        Some('B') => 11,
+        _ => return Err(format!("No such note {}", name)),
+    };
+    let mut octave = chars.as_str();
+    if let Some(rest) = octave.strip_prefix('#') {
+        pitch += 1;
+        octave = rest;
+    } else if let Some(rest) = octave.strip_prefix('b') {
+        pitch -= 1;
+        octave = rest;
+    }
+    let octave: i32 = octave.parse().map_err(|_| format!("No octave in note {}", name))?;
+    let number = (octave + 1) * 12 + pitch;
+    if (0..128).contains(&number) {
+        Ok(number as u8)
+    } else {
+        Err(format!("No such note {}", name))
+    }
+}
+
+fn note_name(note: u8) -> String {
+    format!("{}{}", NOTE_NAMES[note as usize % 12], note as i32 / 12 - 1)
+}
+
+/// Inclusive range of 7 bit values, written `0..100`.
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, Copy)]
+#[serde(try_from = "String", into = "String")]
+pub struct ValueRange {
+    pub low: u8,
+    pub high: u8,
+}
+
+impl Default for ValueRange {
+    fn default() -> Self {
+        ValueRange { low: 0, high: 127 }
+    }
+}
+
+impl TryFrom<String> for ValueRange {
+    type Error = String;
+
+    fn try_from(range: String) -> Result<Self, Self::Error> {
+        let parse = |value: &str| match value.trim().parse::<u8>() {
+            Ok(value) if value < 128 => Ok(value),
+            _ => Err(format!("No such value {} in range {}", value, range)),
+        };
+        let mut ends = range.splitn(2, "..");
+        let low = parse(ends.next().unwrap_or_default())?;
+        let high = parse(ends.next().ok_or(format!("No end to range {}", range))?)?;
+        if low >= high {
+            return Err(format!("Empty value range {}", range));
+        }
+        Ok(ValueRange { low, high })
+    }
+}
+
+impl From<ValueRange> for String {
+    fn from(range: ValueRange) -> Self {
+        range.to_string()
+    }
+}
+
+impl fmt::Display for ValueRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}..{}", self.low, self.high)
+    }
+}
+
+/// Send one controller as another, optionally rescaling its values.
+/// Values outside `in-range` are clamped to it, then spread over `out-range`.
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct CcMapping {
+    pub from_cc: u8,
+    /// Same as `from-cc` if missing, for rescaling only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to_cc: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub in_range: Option<ValueRange>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub out_range: Option<ValueRange>,
+    /// Turn the knob the other way: the low end of `in-range` goes to the high end of `out-range`
+    #[serde(default)]
+    pub invert: bool,
+}
+
+impl CcMapping {
+    pub fn to_cc(&self) -> u8 {
+        self.to_cc.unwrap_or(self.from_cc)
+    }
+
+    pub fn scale(&self, value: i32) -> i32 {
+        let input = self.in_range.unwrap_or_default();
+        let output = self.out_range.unwrap_or_default();
+        let (in_low, in_high) = (input.low as i32, input.high as i32);
+        let mut pos = value.clamp(in_low, in_high) - in_low;
+        if self.invert {
+            pos = in_high - in_low - pos;
+        }
+        let (out_low, out_span) = (output.low as i32, (output.high - output.low) as i32);
+        // rounded to nearest
+        out_low + (pos * out_span * 2 + (in_high - in_low)) / ((in_high - in_low) * 2)
+    }
+}
+
+/// Rework the 24 PPQN clock. Start, stop, continue and song position go through untouched.
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct ClockPrefs {
+    /// Send one tick out of this many, e.g. 2 for half-time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub divide: Option<u8>,
+    /// Send this many ticks for each one, e.g. 2 for double-time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub multiply: Option<u8>,
+    /// Where every other 16th note starts, in percent of an 8th note:
+    /// 50 is straight, 66 a triplet feel, 75 the most.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub swing: Option<u8>,
+}
+
+impl ClockPrefs {
+    /// Outgoing ticks per incoming tick, as (multiply, divide).
+    pub fn ratio(&self) -> (u64, u64) {
+        (self.multiply.unwrap_or(1) as u64, self.divide.unwrap_or(1) as u64)
+    }
+
+    /// Where an outgoing tick should land once swung, in outgoing ticks.
+    /// Ticks of the first 16th of each pair are spread out, those of the second squeezed in.
+    pub fn swung(&self, tick: u64) -> f64 {
+        let swing = self.swing.unwrap_or(50) as f64 / 100.0;
+        let (pair, pos) = ((tick - tick % 12) as f64, (tick % 12) as f64);
+        if pos < 6.0 {
+            pair + pos * 2.0 * swing
+        } else {
+            pair + 12.0 * swing + (pos - 6.0) * 2.0 * (1.0 - swing)
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.divide == Some(0) || self.multiply == Some(0) {
+            return Err("clock can't be divided or multiplied by 0".to_string());
+        }
+        if let Some(swing) = self.swing.filter(|s| !(50..=75).contains(s)) {
+            return Err(format!("clock swing {} out of 50..75", swing));
+        }
+        Ok(())
+    }
+}
+
+/// What routes with a `cc-map` do with controllers it doesn't mention.
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, Copy, Default)]
+pub enum UnmappedCc {
+    #[default]
+    Pass,
+    Drop,
+}
+
+fn is_pass(unmapped: &UnmappedCc) -> bool {
+    *unmapped == UnmappedCc::Pass
+}
+
+/// Point-to-point connection, independent of device roles.
+/// Both ends accept an alias or a port name.
+///
+/// Routes that only connect are plain ALSA subscriptions. Routes that also filter
+/// or transform messages are served by autoroute's own sequencer ports.
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct RoutePrefs {
+    pub from: String,
+    pub to: String,
+    /// Only let these kinds of messages through
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub keep: Vec<MessageKind>,
+    /// Never let these kinds of messages through
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub drop: Vec<MessageKind>,
+    /// Only let channel messages on these channels (1-16) through
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub channels: Vec<u8>,
+    /// Move channel messages from one channel (1-16) to another
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub remap_channels: BTreeMap<u8, u8>,
+    /// Only let notes in this range through, other messages are unaffected.
+    /// Routes from the same controller with different ranges split the keyboard,
+    /// overlapping ranges layer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notes: Option<NoteRange>,
+    /// Shift notes by this many semitones.
+    /// Routes with `transpose` or `octave`, even 0, can also be transposed while running.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transpose: Option<i8>,
+    /// Shift notes by this many octaves, on top of `transpose`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub octave: Option<i8>,
+    /// Send controllers as other controllers. A controller mapped more than once is sent as each.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cc_map: Vec<CcMapping>,
+    /// Let controllers missing from `cc-map` through as they are (`Pass`), or not (`Drop`)
+    #[serde(default, skip_serializing_if = "is_pass")]
+    pub unmapped_cc: UnmappedCc,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clock: Option<ClockPrefs>,
+}
+
+impl RoutePrefs {
+    /// True if messages can go straight from sender to destination, untouched.
+    pub fn is_direct(&self) -> bool {
+        self.keep.is_empty()
+            && self.drop.is_empty()
+            && self.channels.is_empty()
+            && self.remap_channels.is_empty()
+            && self.notes.is_none()
+            && !self.is_transposable()
+            && self.cc_map.is_empty()
+            && is_pass(&self.unmapped_cc)
+            && self.clock.is_none()
+    }
+
+    pub fn is_transposable(&self) -> bool {
+        self.transpose.is_some() || self.octave.is_some()
+    }
+
+    /// Configured transposition, in semitones.
+    pub fn semitones(&self) -> i32 {
+        self.transpose.unwrap_or(0) as i32 + 12 * self.octave.unwrap_or(0) as i32
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let remapped = self.remap_channels.iter().flat_map(|(from, to)| vec![from, to]);
+        if let Some(bad) = self.channels.iter().chain(remapped).find(|c| !(1..=16).contains(*c)) {
+            return Err(format!("Route {} > {}: no such channel {}", self.from, self.to, bad));
+        }
+        let ccs = self.cc_map.iter().flat_map(|m| vec![m.from_cc, m.to_cc()]);
+        if let Some(bad) = ccs.into_iter().find(|cc| *cc > 127) {
+            return Err(format!("Route {} > {}: no such controller {}", self.from, self.to, bad));
+        }
+        if let Some(clock) = &self.clock {
+            clock.validate().map_err(|e| format!("Route {} > {}: {}", self.from, self.to, e))?;
+        }
+        Ok(())
+    }
+
+    /// True if messages of this kind make it through the route's filters.
+    /// Messages of no particular kind only pass if the route doesn't restrict to specific kinds.
+    pub fn passes(&self, kind: Option<MessageKind>) -> bool {
+        match kind {
+            Some(kind) => {
+                (self.keep.is_empty() || self.keep.contains(&kind)) && !self.drop.contains(&kind)
+            }
+            None => self.keep.is_empty(),
+        }
+    }
+}
+
+/// Connections that must never be made, whatever roles or routes say.
+/// Both ends accept an alias or a port name, with `*` and `?` wildcards.
+/// A missing end matches any port.
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct ExcludePrefs {
+    #[serde(default = "any_port")]
+    pub from: String,
+    #[serde(default = "any_port")]
+    pub to: String,
+}
+
+fn any_port() -> String {
+    "*".to_string()
+}
+
+/// A clock source that stops ticking for this long is given up for the next one.
+/// Long enough for a few ticks at 20 BPM.
+const DEFAULT_CLOCK_TIMEOUT_MS: u64 = 500;
+
+/// Well above what a busy controller sends, even over USB,
+/// and well below what a feedback loop makes of it.
+const DEFAULT_RATE_LIMIT: u32 = 5000;
+
+/// Clock generated by autoroute itself, on its `autoroute2 clock` port.
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct InternalClockPrefs {
+    /// In beats per minute
+    pub tempo: f64,
+}
+
+impl InternalClockPrefs {
+    fn validate(&self) -> Result<(), String> {
+        if !(20.0..=300.0).contains(&self.tempo) {
+            return Err(format!("Internal clock tempo {} out of 20..300", self.tempo));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
 pub struct PrefsModel {
+    /// Refuse to load config files containing unknown keys
+    #[serde(default)]
+    pub strict: bool,
+    /// Also remove subscriptions that were not created by autoroute
+    #[serde(default)]
+    pub exclusive: bool,
+    /// Where autoroute keeps track of the subscriptions it created
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state_dir: Option<String>,
     pub devices: Vec<DevicePrefs>,
+    #[serde(default)]
+    pub routes: Vec<RoutePrefs>,
+    #[serde(default)]
+    pub exclude: Vec<ExcludePrefs>,
+    /// Run a clock of our own, for setups without a hardware master
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub internal_clock: Option<InternalClockPrefs>,
+    /// Clock sources (alias or port name) in order of preference. Unlisted ones come last.
+    /// Only the first one present and ticking gets its clock through.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub clock_sources: Vec<String>,
+    /// How long a clock source can go without ticking before the next one takes over
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clock_timeout_ms: Option<u64>,
+    /// Messages per second a single port may send before its routes are cut, as a feedback loop
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<u32>,
 }
 
 #[derive(Debug, Clone, Default)]
 pub struct UserPrefs {
     pub prefs_model: PrefsModel,
-    known_ports: HashMap<String, DevicePrefs>,
     port_alias: HashMap<String, String>,
+    /// Compiled `port-name` regexes, by `port-name`
+    port_regex: HashMap<String, Regex>,
 }
 
 impl UserPrefs {
     pub fn load_from<R: Read>(read: R) -> Result<Self, Box<dyn Error>> {
-        let prefs: PrefsModel = serde_yaml::from_reader(read)?;
-        let ports = prefs.devices.iter().map(|d| (d.port_name.clone(), d.clone())).collect();
+        let raw: Value = serde_yaml::from_reader(read)?;
+        let mut unknown = vec![];
+        let prefs: PrefsModel =
+            serde_ignored::deserialize(raw, |path| unknown.push(key_path(&path)))?;
+        if prefs.strict && !unknown.is_empty() {
+            return Err(format!("Unknown config keys: {}", unknown.join(", ")).into());
+        }
+        for key in unknown {
+            eprintln!("Ignoring unknown config key {}", key)
+        }
+        for route in &prefs.routes {
+            route.validate()?;
+        }
+        let mut port_regex = HashMap::new();
+        for device in &prefs.devices {
+            if let Some(velocity) = &device.velocity {
+                velocity.validate(&device.port_name)?;
+            }
+            if let Some(regex) = device.regex() {
+                // the whole name has to match, as with globs
+                match Regex::new(&format!("^(?:{})$", regex)) {
+                    Ok(compiled) => port_regex.insert(device.port_name.clone(), compiled),
+                    Err(e) => {
+                        return Err(format!("Bad port-name {}: {}", device.port_name, e).into())
+                    }
+                };
+            }
+        }
+        if let Some(clock) = &prefs.internal_clock {
+            clock.validate()?;
+        }
+
         let alias = prefs
             .devices
             .iter()
             .filter_map(|d| d.alias.as_ref().map(|a| (a.clone(), d.port_name.clone())))
             .collect();
-        Ok(UserPrefs { prefs_model: prefs, known_ports: ports, port_alias: alias })
+        Ok(UserPrefs { prefs_model: prefs, port_alias: alias, port_regex })
+    }
+
+    pub fn rate_limit(&self) -> u32 {
+        self.prefs_model.rate_limit.unwrap_or(DEFAULT_RATE_LIMIT)
+    }
+
+    /// Stop routing anything from this port, e.g. because it's stuck in a feedback loop.
+    /// Its roles, routes and links no longer apply; connections made by hand are not affected.
+    pub fn cut(&mut self, port: &MidiPort) {
+        self.prefs_model.exclude.push(ExcludePrefs { from: port.id(), to: any_port() })
+    }
+
+    pub fn clock_timeout(&self) -> Duration {
+        Duration::from_millis(self.prefs_model.clock_timeout_ms.unwrap_or(DEFAULT_CLOCK_TIMEOUT_MS))
+    }
+
+    /// Configured state dir, else the one systemd provides, else the user's XDG state dir.
+    pub fn state_dir(&self) -> PathBuf {
+        if let Some(dir) = &self.prefs_model.state_dir {
+            return PathBuf::from(dir);
+        }
+        if let Some(dir) = env::var_os("STATE_DIRECTORY") {
+            if let Some(dir) = env::split_paths(&dir).next() {
+                return dir;
+            }
+        }
+        if let Some(dir) = env::var_os("XDG_STATE_HOME") {
+            return Path::new(&dir).join("autoroute2");
+        }
+        if let Some(home) = env::var_os("HOME") {
+            return Path::new(&home).join(".local/state/autoroute2");
+        }
+        PathBuf::from("/var/lib/autoroute2")
+    }
+
+    /// Devices naming the port exactly come first. Devices sharing a port name are told apart
+    /// by their selectors if they have any. Otherwise they are matched in config order,
+    /// by order of appearance. Failing that, the first glob or regex matching the port is used.
+    pub fn get_port_prefs(&self, port: &MidiPort) -> Option<&DevicePrefs> {
+        let exact: Vec<&DevicePrefs> =
+            self.prefs_model.devices.iter().filter(|d| d.names(port)).collect();
+        if let Some(selected) = exact.iter().find(|d| d.has_selectors() && d.selects(port)) {
+            return Some(selected);
+        }
+        let unselected: Vec<&DevicePrefs> =
+            exact.into_iter().filter(|d| !d.has_selectors()).collect();
+        let by_instance = match unselected.len() {
+            1 => Some(unselected[0]),
+            _ => unselected.get(port.instance).copied(),
+        };
+        by_instance.or_else(|| self.pattern_prefs(port).first().copied())
+    }
+
+    /// Devices whose glob or regex `port-name` match the port, in config order.
+    fn pattern_prefs(&self, port: &MidiPort) -> Vec<&DevicePrefs> {
+        let client_port = port.client_port();
+        self.prefs_model
+            .devices
+            .iter()
+            .filter(|d| {
+                let matches = |name: &str| match self.port_regex.get(&d.port_name) {
+                    Some(regex) => regex.is_match(name),
+                    None => d.is_glob() && wildcard_match(&d.port_name, name),
+                };
+                (matches(&port.name) || matches(&client_port)) && d.selects(port)
+            })
+            .collect()
+    }
+
+    /// Ports matched by more than one glob or regex, and by no exact `port-name`.
+    pub fn ambiguous_matches(&self, ports: &[MidiPort]) -> Vec<String> {
+        let mut warnings = vec![];
+        for port in ports {
+            if self.prefs_model.devices.iter().any(|d| d.names(port)) {
+                continue;
+            }
+            let matching = self.pattern_prefs(port);
+            if matching.len() > 1 {
+                let names: Vec<&str> = matching.iter().map(|d| d.port_name.as_str()).collect();
+                warnings.push(format!(
+                    "Port {} matches devices {}, using the first",
+                    port.id(),
+                    names.join(", ")
+                ));
+            }
+        }
+        warnings
+    }
+
+    /// Devices for the ports no config entry matches yet.
+    pub fn new_devices(&self, ports: &[MidiPort]) -> Vec<DevicePrefs> {
+        let mut devices: Vec<DevicePrefs> = vec![];
+        for port in ports {
+            if self.get_port_prefs(port).is_none()
+                && !devices.iter().any(|d| d.port_name == port.name)
+            {
+                devices.push(DevicePrefs::from_port(port))
+            }
+        }
+        devices
+    }
+
+    /// Configured devices matching none of the ports.
+    pub fn unseen_devices(&self, ports: &[MidiPort]) -> Vec<&DevicePrefs> {
+        let seen: Vec<&DevicePrefs> = ports.iter().filter_map(|p| self.get_port_prefs(p)).collect();
+        self.prefs_model
+            .devices
+            .iter()
+            .filter(|d| !seen.iter().any(|s| std::ptr::eq(*s, *d)))
+            .collect()
+    }
+
+    pub fn velocity(&self, port: &MidiPort) -> Option<&VelocityPrefs> {
+        self.get_port_prefs(port).and_then(|pconf| pconf.velocity.as_ref())
+    }
+
+    /// The port can be subscribed to, and the config doesn't make it output only.
+    pub fn sends(&self, port: &MidiPort) -> bool {
+        let dir = self.get_port_prefs(port).and_then(|pconf| pconf.port_dir.as_ref());
+        port.can_send() && dir != Some(&PortDir::Output)
+    }
+
+    /// The port can be subscribed to others, and the config doesn't make it input only.
+    pub fn receives(&self, port: &MidiPort) -> bool {
+        let dir = self.get_port_prefs(port).and_then(|pconf| pconf.port_dir.as_ref());
+        port.can_receive() && dir != Some(&PortDir::Input)
+    }
+
+    pub fn is_ignored(&self, port: &MidiPort) -> bool {
+        self.get_port_prefs(port).map(|pconf| pconf.ignore).unwrap_or(false)
+    }
+
+    pub fn resolve_to_alias(&self, port: &MidiPort) -> String {
+        self.get_port_prefs(port).and_then(|pconf| pconf.alias.clone()).unwrap_or(port.id())
+    }
+
+    /// Find the ports designated by an alias, or else by a port name.
+    pub fn resolve_ports<'a>(&self, name: &str, ports: &'a [MidiPort]) -> Vec<&'a MidiPort> {
+        if self.port_alias.contains_key(name) {
+            ports
+                .iter()
+                .filter(|p| {
+                    self.get_port_prefs(p).and_then(|pconf| pconf.alias.as_deref()) == Some(name)
+                })
+                .collect()
+        } else {
+            ports.iter().filter(|p| p.name == name || p.id() == name).collect()
+        }
+    }
+
+    pub fn is_excluded(&self, sender: &MidiPort, dest: &MidiPort) -> bool {
+        self.prefs_model
+            .exclude
+            .iter()
+            .any(|ex| self.designates(&ex.from, sender) && self.designates(&ex.to, dest))
+    }
+
+    /// True if the wildcard pattern matches the port's alias, name or numbered name.
+    fn designates(&self, pattern: &str, port: &MidiPort) -> bool {
+        let alias = self.get_port_prefs(port).and_then(|pconf| pconf.alias.as_deref());
+        alias.map(|a| wildcard_match(pattern, a)).unwrap_or(false)
+            || wildcard_match(pattern, &port.name)
+            || wildcard_match(pattern, &port.id())
     }
+}
+
+/// Append `devices` to the config file at `path`, leaving everything else in it as it was,
+/// comments included. The previous version is kept next to it with a `.bak` suffix.
+pub fn merge_devices(path: &Path, devices: &[DevicePrefs]) -> Result<(), Box<dyn Error>> {
+    let merged = with_devices(&fs::read_to_string(path)?, devices)?;
+    // never write something autoroute can't load back
+    UserPrefs::load_from(merged.as_bytes())?;
+
+    let with_suffix = |suffix: &str| {
+        let mut name = OsString::from(path.as_os_str());
+        name.push(suffix);
+        PathBuf::from(name)
+    };
+    let tmp = with_suffix(".tmp");
+    let mut file = File::create(&tmp)?;
+    // restrict the copy before writing to it, the config may not be meant for everyone
+    file.set_permissions(fs::metadata(path)?.permissions())?;
+    file.write_all(merged.as_bytes())?;
+    file.sync_all()?;
+    fs::copy(path, with_suffix(".bak"))?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
 
-    pub fn save_to<W: Write>(&self, write: &mut W) -> Result<(), Box<dyn Error>> {
-        Ok(serde_yaml::to_writer(write, &self.prefs_model)?)
+/// The config text with `devices` added after the last entry of its `devices` list.
+fn with_devices(config: &str, devices: &[DevicePrefs]) -> Result<String, Box<dyn Error>> {
+    let mut lines: Vec<String> = config.lines().map(str::to_string).collect();
+    let top_level = |line: &str| !line.is_empty() && !line.starts_with(&[' ', '#', '-'][..]);
+    let (key, insert_at) = match lines.iter().position(|l| l.starts_with("devices:")) {
+        Some(key) => {
+            let value = lines[key]["devices:".len()..].split('#').next().unwrap_or("").trim();
+            match value {
+                "" => {}
+                "[]" => lines[key] = "devices:".to_string(),
+                _ => return Err("Can't add to a devices list written inline".into()),
+            }
+            let end = lines[key + 1..].iter().position(|l| top_level(l)).map(|e| key + 1 + e);
+            // after the last entry, before comments about what follows
+            let last = lines[key..end.unwrap_or(lines.len())]
+                .iter()
+                .rposition(|l| !l.trim().is_empty() && !l.trim_start().starts_with('#'))
+                .map(|l| key + l)
+                .unwrap_or(key);
+            (key, last + 1)
+        }
+        None => {
+            lines.push("devices:".to_string());
+            (lines.len() - 1, lines.len())
+        }
+    };
+    let indent = lines[key + 1..insert_at]
+        .iter()
+        .find(|l| l.trim_start().starts_with("- "))
+        .map(|l| l.len() - l.trim_start().len())
+        .unwrap_or(2);
+    let mut added = vec![];
+    for device in devices {
+        let yaml = serde_yaml::to_string(device)?;
+        let body = yaml.trim_start_matches("---\n").lines();
+        for (i, line) in body.enumerate() {
+            let lead = if i == 0 { "- " } else { "  " };
+            added.push(format!("{}{}{}", " ".repeat(indent), lead, line));
+        }
     }
+    lines.splice(insert_at..insert_at, added);
+    let mut merged = lines.join("\n");
+    merged.push('\n');
+    Ok(merged)
+}
+
+/// Where a key sits in the config, e.g. `routes[0].keep`.
+fn key_path(path: &serde_ignored::Path) -> String {
+    match path {
+        serde_ignored::Path::Root => String::new(),
+        serde_ignored::Path::Seq { parent, index } => format!("{}[{}]", key_path(parent), index),
+        serde_ignored::Path::Map { parent, key } => match key_path(parent) {
+            parent if parent.is_empty() => key.clone(),
+            parent => format!("{}.{}", parent, key),
+        },
+        serde_ignored::Path::Some { parent }
+        | serde_ignored::Path::NewtypeStruct { parent }
+        | serde_ignored::Path::NewtypeVariant { parent } => key_path(parent),
+    }
+}
+
+/// Shell-style matching where `*` matches any run of characters and `?` any single one.
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // position of the last `*` seen, and of the text it was tried against
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                // let the last `*` swallow one more character
+                Some((star, star_t)) => {
+                    backtrack = Some((star, star_t + 1));
+                    p = star + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ports::tests::port;
+    use crate::usb::UsbInfo;
+    use alsa::seq::PortCap;
+
+    fn load(yaml: &str) -> Result<UserPrefs, Box<dyn Error>> {
+        UserPrefs::load_from(yaml.as_bytes())
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("*", "anything"));
+        assert!(wildcard_match("a*b*c", "aXbYbZc"));
+        assert!(wildcard_match("a*bc", "abcbc"));
+        assert!(wildcard_match("*ab", "aab"));
+        assert!(wildcard_match("M?D*", "MIDI 1"));
+        assert!(wildcard_match("Neutron**", "Neutron"));
+        assert!(!wildcard_match("a*b*c", "aXbYcZ"));
+        assert!(!wildcard_match("?", ""));
+        assert!(!wildcard_match("a?", "a"));
+        assert!(!wildcard_match("", "a"));
+        assert!(!wildcard_match("neutron", "Neutron"));
+    }
+
+    #[test]
+    fn note_names() {
+        assert_eq!(parse_note("C-1"), Ok(0));
+        assert_eq!(parse_note("C4"), Ok(60));
+        assert_eq!(parse_note(" c#4 "), Ok(61));
+        assert_eq!(parse_note("Eb-1"), Ok(3));
+        assert_eq!(parse_note("G9"), Ok(127));
+        assert_eq!(parse_note("127"), Ok(127));
+        assert!(parse_note("G#9").is_err());
+        assert!(parse_note("Cb-1").is_err());
+        assert!(parse_note("128").is_err());
+        assert!(parse_note("H2").is_err());
+        assert!(parse_note("C").is_err());
+        assert_eq!(note_name(61), "C#4");
+    }
+
+    #[test]
+    fn note_ranges() {
+        let range = NoteRange::try_from("C-1..B2".to_string()).unwrap();
+        assert_eq!(range, NoteRange { low: 0, high: 47 });
+        assert!(range.contains(0) && range.contains(47) && !range.contains(48));
+        assert_eq!(String::from(range), "C-1..B2");
+        // a single note
+        assert_eq!(NoteRange::try_from("60".to_string()), Ok(NoteRange { low: 60, high: 60 }));
+        assert!(NoteRange::try_from("C4..B3".to_string()).is_err());
+    }
+
+    #[test]
+    fn velocity_curves() {
+        for curve in [VelocityCurve::Exponential, VelocityCurve::Logarithmic] {
+            let prefs = VelocityPrefs { curve: Some(curve), ..Default::default() };
+            // ends stay put, except 0 which would be a note off
+            assert_eq!((prefs.apply(0), prefs.apply(127)), (1, 127));
+            let curved: Vec<u8> = (0..128).map(|v| prefs.apply(v)).collect();
+            assert!(curved.windows(2).all(|pair| pair[0] <= pair[1]));
+        }
+        let soft = VelocityPrefs { curve: Some(VelocityCurve::Exponential), ..Default::default() };
+        let loud = VelocityPrefs { curve: Some(VelocityCurve::Logarithmic), ..Default::default() };
+        assert!(soft.apply(64) < 64 && loud.apply(64) > 64);
+        let table = VelocityPrefs {
+            curve: Some(VelocityCurve::Table((0..128).rev().collect())),
+            ..Default::default()
+        };
+        assert_eq!((table.apply(0), table.apply(1), table.apply(127)), (127, 126, 1));
+    }
+
+    #[test]
+    fn velocity_limits() {
+        let prefs = VelocityPrefs {
+            scale: Some(0.5),
+            offset: Some(-10),
+            min: Some(20),
+            max: Some(50),
+            ..Default::default()
+        };
+        assert_eq!((prefs.apply(1), prefs.apply(80), prefs.apply(127)), (20, 30, 50));
+        let fixed = VelocityPrefs { fixed: Some(90), ..prefs };
+        assert_eq!(fixed.apply(1), 90);
+        let negative = VelocityPrefs { offset: Some(-200), ..Default::default() };
+        assert_eq!(negative.apply(127), 1);
+    }
+
+    #[test]
+    fn velocity_validation() {
+        let validate = |yaml| serde_yaml::from_str::<VelocityPrefs>(yaml).unwrap().validate("X");
+        assert!(validate("fixed: 100").is_ok());
+        assert!(validate("fixed: 0").is_err());
+        assert!(validate("max: 128").is_err());
+        assert!(validate("{min: 60, max: 50}").is_err());
+        assert!(validate("curve: {table: [1, 2, 3]}").is_err());
+    }
+
+    #[test]
+    fn value_ranges() {
+        let range = ValueRange::try_from(" 10 .. 100".to_string());
+        assert_eq!(range, Ok(ValueRange { low: 10, high: 100 }));
+        assert!(ValueRange::try_from("100..10".to_string()).is_err());
+        assert!(ValueRange::try_from("64..64".to_string()).is_err());
+        assert!(ValueRange::try_from("0..128".to_string()).is_err());
+        assert!(ValueRange::try_from("0".to_string()).is_err());
+        assert!(ValueRange::try_from("0..".to_string()).is_err());
+    }
+
+    #[test]
+    fn cc_scaling() {
+        let range = |low, high| Some(ValueRange { low, high });
+        let mapping = CcMapping {
+            from_cc: 1,
+            to_cc: None,
+            in_range: None,
+            out_range: range(0, 100),
+            invert: false,
+        };
+        assert_eq!(mapping.to_cc(), 1);
+        let scaled: Vec<i32> = [0, 1, 63, 64, 126, 127].iter().map(|&v| mapping.scale(v)).collect();
+        assert_eq!(scaled, vec![0, 1, 50, 50, 99, 100]);
+        let inverted = CcMapping { in_range: range(20, 40), invert: true, ..mapping };
+        assert_eq!(inverted.scale(0), 100);
+        assert_eq!(inverted.scale(20), 100);
+        assert_eq!(inverted.scale(30), 50);
+        assert_eq!(inverted.scale(40), 0);
+        assert_eq!(inverted.scale(127), 0);
+        let narrow = CcMapping { in_range: range(0, 1), out_range: range(64, 127), ..inverted };
+        assert_eq!((narrow.scale(0), narrow.scale(1)), (127, 64));
+    }
+
+    #[test]
+    fn clock_swing() {
+        let straight = ClockPrefs::default();
+        assert_eq!(straight.ratio(), (1, 1));
+        assert!((0..48).all(|tick| straight.swung(tick) == tick as f64));
+        let swung = ClockPrefs { swing: Some(75), ..Default::default() };
+        assert_eq!((swung.swung(3), swung.swung(6), swung.swung(11)), (4.5, 9.0, 11.5));
+        assert_eq!(swung.swung(12), 12.0);
+        assert!((0..48).all(|tick| swung.swung(tick) < swung.swung(tick + 1)));
+    }
+
+    #[test]
+    fn clock_validation() {
+        let clock = |yaml| serde_yaml::from_str::<ClockPrefs>(yaml).unwrap().validate();
+        assert!(clock("{divide: 3, multiply: 2, swing: 66}").is_ok());
+        assert!(clock("divide: 0").is_err());
+        assert!(clock("multiply: 0").is_err());
+        assert!(clock("swing: 49").is_err());
+        assert!(clock("swing: 76").is_err());
+    }
+
+    #[test]
+    fn excludes_by_alias_name_or_id() {
+        let prefs = load(
+            "devices:
+  - port-name: Neutron
+    alias: bass
+exclude:
+  - from: bass
+  - to: 'TB-3 #2'
+  - {from: Beat*, to: '*D-8'}
+",
+        )
+        .unwrap();
+        let (neutron, beatstep) = (port(20, "Neutron"), port(21, "BeatStep"));
+        let rd8 = port(22, "RD-8");
+        let first_tb3 = port(23, "TB-3");
+        let second_tb3 = MidiPort { instance: 1, ..port(24, "TB-3") };
+        assert!(prefs.is_excluded(&neutron, &rd8));
+        assert!(!prefs.is_excluded(&rd8, &neutron));
+        assert!(prefs.is_excluded(&beatstep, &second_tb3));
+        assert!(!prefs.is_excluded(&beatstep, &first_tb3));
+        assert!(prefs.is_excluded(&beatstep, &rd8));
+        assert!(!prefs.is_excluded(&rd8, &beatstep));
+    }
+
+    #[test]
+    fn direction_from_caps_and_port_dir() {
+        let prefs = load(
+            "devices:
+  - port-name: RD-8
+    port-dir: Output
+  - port-name: Neutron
+    port-dir: Input
+",
+        )
+        .unwrap();
+        let beatstep = port(20, "BeatStep");
+        assert!(prefs.sends(&beatstep) && prefs.receives(&beatstep));
+        let keyboard = MidiPort { caps: PortCap::READ | PortCap::SUBS_READ, ..port(21, "Keystep") };
+        assert!(prefs.sends(&keyboard) && !prefs.receives(&keyboard));
+        // without SUBS_READ, nothing can subscribe to the port
+        let hidden = MidiPort { caps: PortCap::READ | PortCap::WRITE, ..port(22, "Hidden") };
+        assert!(!prefs.sends(&hidden) && !prefs.receives(&hidden));
+        let (rd8, neutron) = (port(23, "RD-8"), port(24, "Neutron"));
+        assert!(!prefs.sends(&rd8) && prefs.receives(&rd8));
+        assert!(prefs.sends(&neutron) && !prefs.receives(&neutron));
+        // port-dir narrows what the caps allow, it can't widen it
+        let input_only = MidiPort { caps: PortCap::WRITE | PortCap::SUBS_WRITE, ..neutron };
+        assert!(!prefs.sends(&input_only) && !prefs.receives(&input_only));
+    }
+
+    /// Alias of the device prefs matching the port.
+    fn alias<'a>(prefs: &'a UserPrefs, port: &MidiPort) -> Option<&'a str> {
+        prefs.get_port_prefs(port).and_then(|d| d.alias.as_deref())
+    }
+
+    #[test]
+    fn exact_names_beat_patterns() {
+        let prefs = load(
+            "devices:
+  - {port-name: 'Neutron*', alias: glob}
+  - {port-name: '/^Neut.*n$/', alias: regex}
+  - {port-name: Neutron, alias: exact}
+  - {port-name: 'TB-3:1', alias: numbered}
+  - {port-name: '/^RD-\\d$/', alias: drum}
+",
+        )
+        .unwrap();
+        assert_eq!(alias(&prefs, &port(20, "Neutron")), Some("exact"));
+        assert_eq!(alias(&prefs, &port(20, "Neutron(1)")), Some("glob"));
+        assert_eq!(alias(&prefs, &port(20, "RD-8")), Some("drum"));
+        assert_eq!(alias(&prefs, &port(20, "RD-88")), None);
+        let mut second = port(21, "TB-3");
+        assert_eq!(alias(&prefs, &second), None);
+        second.addr.port = 1;
+        assert_eq!(alias(&prefs, &second), Some("numbered"));
+    }
+
+    #[test]
+    fn selectors_and_instances() {
+        let prefs = load(
+            "devices:
+  - {port-name: TB-3, alias: first}
+  - {port-name: TB-3, alias: second}
+  - {port-name: TB-3, usb-serial: ABC, alias: serial}
+  - {port-name: Neutron, instance: 1, alias: other}
+  - {port-name: Neutron, alias: any}
+",
+        )
+        .unwrap();
+        let instance = |client, name, instance| MidiPort { instance, ..port(client, name) };
+        assert_eq!(alias(&prefs, &port(20, "TB-3")), Some("first"));
+        assert_eq!(alias(&prefs, &instance(21, "TB-3", 1)), Some("second"));
+        assert_eq!(alias(&prefs, &instance(22, "TB-3", 2)), None);
+        let usb = UsbInfo { path: "1-1.2".to_string(), serial: Some("ABC".to_string()) };
+        let serial = MidiPort { usb: Some(usb), ..instance(22, "TB-3", 2) };
+        assert_eq!(alias(&prefs, &serial), Some("serial"));
+        // a lone unselected entry takes every instance the selected ones leave
+        assert_eq!(alias(&prefs, &port(23, "Neutron")), Some("any"));
+        assert_eq!(alias(&prefs, &instance(24, "Neutron", 1)), Some("other"));
+        assert_eq!(alias(&prefs, &instance(25, "Neutron", 2)), Some("any"));
+    }
+
+    #[test]
+    fn warns_of_ambiguous_patterns() {
+        let prefs = load(
+            "devices:
+  - port-name: 'Neut*'
+  - port-name: '/.*tron/'
+  - port-name: 'Neutron 2'
+",
+        )
+        .unwrap();
+        let ports = [port(20, "Neutron"), port(21, "Neutron 2"), port(22, "BeatStep")];
+        let warning = "Port Neutron matches devices Neut*, /.*tron/, using the first";
+        assert_eq!(prefs.ambiguous_matches(&ports), vec![warning.to_string()]);
+    }
+
+    #[test]
+    fn strict_accepts_defaults_written_out() {
+        let prefs = load(
+            "strict: true
+clock-sources: []
+devices:
+  - port-name: BeatStep
+    port-dir: Input
+    roles: []
+    ignore: false
+routes:
+  - from: BeatStep
+    to: RD-8
+    unmapped-cc: Pass
+    keep: []
+    drop: []
+",
+        );
+        assert!(prefs.is_ok(), "{:?}", prefs.err());
+    }
+
+    #[test]
+    fn strict_rejects_unknown_keys() {
+        let err = load(
+            "strict: true
+devices: []
+routes:
+  - from: BeatStep
+    to: RD-8
+    bogus: 1
+frobnicate: true
+",
+        )
+        .err()
+        .unwrap()
+        .to_string();
+        assert!(err.contains("routes[0].bogus"), "{}", err);
+        assert!(err.contains("frobnicate"), "{}", err);
+    }
+
+    fn new_device(name: &str) -> DevicePrefs {
+        DevicePrefs { port_name: name.to_string(), ..DevicePrefs::default() }
+    }
+
+    #[test]
+    fn merge_keeps_comments_and_order() {
+        let config = "# my rig
+devices:
+  - port-name: Pyramid
+    # the master
+    alias: Pyramid
+  # old synth
+  #- port-name: Gone
+# routes come later
+#routes:
+routes:
+  - from: Pyramid
+    to: Neutron(1) MIDI 1
+";
+        let merged = with_devices(config, &[new_device("Neutron(1) MIDI 1")]).unwrap();
+        assert_eq!(
+            merged,
+            "# my rig
+devices:
+  - port-name: Pyramid
+    # the master
+    alias: Pyramid
+  - port-name: Neutron(1) MIDI 1
+    alias: ~
+    roles: []
+    ignore: false
+  # old synth
+  #- port-name: Gone
+# routes come later
+#routes:
+routes:
+  - from: Pyramid
+    to: Neutron(1) MIDI 1
+"
+        );
+        let prefs = load(&merged).unwrap();
+        assert_eq!(prefs.prefs_model.devices.len(), 2);
+        assert_eq!(prefs.prefs_model.routes.len(), 1);
+    }
+
+    #[test]
+    fn merge_into_empty_or_missing_devices() {
+        for config in &["devices: []\nrate-limit: 100\n", "rate-limit: 100\n", "devices:\n"] {
+            let merged = with_devices(config, &[new_device("A"), new_device("B")]).unwrap();
+            let prefs = load(&merged).unwrap();
+            let names: Vec<&str> =
+                prefs.prefs_model.devices.iter().map(|d| d.port_name.as_str()).collect();
+            assert_eq!(names, vec!["A", "B"], "{}", merged);
+        }
+    }
+
+    #[test]
+    fn merge_file_in_place() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = env::temp_dir().join(format!("autoroute2-merge-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.yaml");
+        let config = "# keep me\ndevices: []\n";
+        fs::write(&path, config).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
 
-    pub fn get_port_prefs(&self, port_name: &str) -> Option<&DevicePrefs> {
-        self.known_ports.get(port_name)
+        merge_devices(&path, &[new_device("A")]).unwrap();
+        let merged = fs::read_to_string(&path).unwrap();
+        assert!(merged.starts_with("# keep me\ndevices:\n  - port-name: A\n"), "{}", merged);
+        assert_eq!(fs::read_to_string(dir.join("config.yaml.bak")).unwrap(), config);
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        fs::remove_dir_all(&dir).unwrap();
     }
 
-    pub fn resolve_to_alias(&self, name: &str) -> String {
-        self.known_ports.get(name).and_then(|pconf| pconf.alias.clone()).unwrap_or(name.to_string())
+    #[test]
+    fn merge_at_column_zero() {
+        let merged = with_devices("devices:\n- port-name: A\n", &[new_device("B")]).unwrap();
+        assert!(merged.contains("\n- port-name: B\n"), "{}", merged);
+        assert_eq!(load(&merged).unwrap().prefs_model.devices.len(), 2);
     }
 
-    pub fn resolve_to_portname(&self, name: &str) -> String {
-        self.port_alias.get(name).map(|port_name| port_name.clone()).unwrap_or(name.to_string())
+    #[test]
+    fn lenient_skips_unknown_keys() {
+        let prefs = load("devices: []\nfrobnicate: true\n").unwrap();
+        assert!(prefs.prefs_model.devices.is_empty());
     }
 }
@@ -0,0 +1,434 @@
+use crate::prefs::{ClockPrefs, MessageKind, RoutePrefs, UnmappedCc, VelocityPrefs};
+use alsa::seq::{EvCtrl, EvNote, Event, EventType};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Clock ticks per 16th note, at 24 PPQN.
+const TICKS_PER_16TH: u64 = 6;
+
+/// A message to send, and how long to hold it back.
+pub type Output = (Event<'static>, Duration);
+
+/// Per-link message processing, following the link's route prefs.
+/// Keeps whatever state transforms need between messages.
+pub struct Pipeline {
+    route: RoutePrefs,
+    velocity: Option<VelocityPrefs>,
+    /// Runtime transposition, on top of the route's, in semitones
+    shift: i32,
+    /// Held notes by (channel, incoming note), and what they were sent as.
+    /// Releases go out as whatever note was started, even if the transposition changed since.
+    held: HashMap<(u8, u8), u8>,
+    /// Incoming clock ticks since start or song position
+    ticks: u64,
+    last_tick: Option<Instant>,
+    tick_interval: Duration,
+}
+
+impl Pipeline {
+    pub fn new(route: RoutePrefs, velocity: Option<VelocityPrefs>) -> Self {
+        Pipeline {
+            route,
+            velocity,
+            shift: 0,
+            held: HashMap::new(),
+            ticks: 0,
+            last_tick: None,
+            tick_interval: Duration::ZERO,
+        }
+    }
+
+    pub fn set_shift(&mut self, semitones: i32) {
+        self.shift = semitones;
+    }
+
+    /// Note offs for every note still held, for when the link goes away.
+    pub fn release(&mut self) -> Vec<Event<'static>> {
+        self.held
+            .drain()
+            .map(|((channel, _), note)| {
+                let off = EvNote { channel, note, velocity: 0, off_velocity: 0, duration: 0 };
+                Event::new(EventType::Noteoff, &off)
+            })
+            .collect()
+    }
+
+    /// Turn one incoming message into whatever should be sent on, possibly nothing.
+    pub fn process(&mut self, event: &Event) -> Vec<Output> {
+        if !self.route.passes(kind_of(event.get_type())) {
+            return vec![];
+        }
+        let mut event = event.clone().into_owned();
+
+        if let Some(clock) = self.route.clock.clone() {
+            if let Some(ticks) = self.clock(&clock, &event) {
+                return ticks;
+            }
+        }
+
+        if let Some(channel) = channel_of(&event) {
+            let channel = channel + 1;
+            if !self.route.channels.is_empty() && !self.route.channels.contains(&channel) {
+                return vec![];
+            }
+            if let Some(to) = self.route.remap_channels.get(&channel) {
+                event = with_channel(&event, to - 1);
+            }
+        }
+
+        if event.get_type() == EventType::Controller {
+            if let Some(mapped) = event.get_data::<EvCtrl>().and_then(|ctrl| self.map_cc(ctrl)) {
+                return mapped.into_iter().map(|event| (event, Duration::ZERO)).collect();
+            }
+        }
+
+        if let Some(note) = note_of(&event) {
+            if let Some(range) = &self.route.notes {
+                if !range.contains(note.note) {
+                    return vec![];
+                }
+            }
+            let shifted = match self.transpose(event.get_type(), &note) {
+                Some(shifted) => shifted,
+                None => return vec![],
+            };
+            let velocity = match &self.velocity {
+                Some(prefs) if is_note_on(event.get_type(), &note) => prefs.apply(note.velocity),
+                _ => note.velocity,
+            };
+            event = Event::new(event.get_type(), &EvNote { note: shifted, velocity, ..note });
+        }
+        vec![(event, Duration::ZERO)]
+    }
+
+    /// Ticks to send for a clock tick, `None` for other messages, which go on untouched.
+    /// Outgoing ticks falling between two incoming ones are spaced by the last incoming interval.
+    fn clock(&mut self, clock: &ClockPrefs, event: &Event<'static>) -> Option<Vec<Output>> {
+        match event.get_type() {
+            EventType::Clock => {}
+            EventType::Start => {
+                self.ticks = 0;
+                self.last_tick = None;
+                return None;
+            }
+            EventType::Stop | EventType::Continue => {
+                // no interval to measure across a pause
+                self.last_tick = None;
+                return None;
+            }
+            EventType::Songpos => {
+                if let Some(pos) = event.get_data::<EvCtrl>() {
+                    self.ticks = pos.value.max(0) as u64 * TICKS_PER_16TH;
+                }
+                return None;
+            }
+            _ => return None,
+        }
+        let now = Instant::now();
+        if let Some(last) = self.last_tick {
+            self.tick_interval = now - last;
+        }
+        self.last_tick = Some(now);
+        let tick = self.ticks;
+        self.ticks += 1;
+
+        // outgoing ticks landing within [tick, tick + 1) on the incoming time line
+        let (multiply, divide) = clock.ratio();
+        let first = (tick * multiply).div_ceil(divide);
+        let end = ((tick + 1) * multiply).div_ceil(divide);
+        let ticks = (first..end)
+            .map(|out| {
+                let at = clock.swung(out) * divide as f64 / multiply as f64;
+                (event.clone(), self.tick_interval.mul_f64((at - tick as f64).max(0.0)))
+            })
+            .collect();
+        Some(ticks)
+    }
+
+    /// Controllers to send for a 7 bit controller, or `None` to let it through untouched.
+    fn map_cc(&self, ctrl: EvCtrl) -> Option<Vec<Event<'static>>> {
+        let mapped: Vec<Event<'static>> = self
+            .route
+            .cc_map
+            .iter()
+            .filter(|m| m.from_cc as u32 == ctrl.param)
+            .map(|m| {
+                let mapped = EvCtrl { param: m.to_cc() as u32, value: m.scale(ctrl.value), ..ctrl };
+                Event::new(EventType::Controller, &mapped)
+            })
+            .collect();
+        if mapped.is_empty() && self.route.unmapped_cc == UnmappedCc::Pass {
+            return None;
+        }
+        Some(mapped)
+    }
+
+    /// Note to send for a note message, if still within MIDI range.
+    fn transpose(&mut self, event_type: EventType, note: &EvNote) -> Option<u8> {
+        let key = (note.channel, note.note);
+        let shifted = note.note as i32 + self.route.semitones() + self.shift;
+        let current = if (0..128).contains(&shifted) { Some(shifted as u8) } else { None };
+        match event_type {
+            EventType::Noteon if note.velocity > 0 => {
+                if let Some(shifted) = current {
+                    self.held.insert(key, shifted);
+                }
+                current
+            }
+            // note on with velocity 0 is a release too
+            EventType::Noteon | EventType::Noteoff => self.held.remove(&key).or(current),
+            _ => self.held.get(&key).copied().or(current),
+        }
+    }
+}
+
+/// Note messages that start a note, as opposed to releases and aftertouch.
+fn is_note_on(event_type: EventType, note: &EvNote) -> bool {
+    match event_type {
+        EventType::Note | EventType::Noteon => note.velocity > 0,
+        _ => false,
+    }
+}
+
+/// Note data of messages about a specific key.
+fn note_of(event: &Event) -> Option<EvNote> {
+    match event.get_type() {
+        EventType::Note | EventType::Noteon | EventType::Noteoff | EventType::Keypress => {
+            event.get_data::<EvNote>()
+        }
+        _ => None,
+    }
+}
+
+pub fn kind_of(event_type: EventType) -> Option<MessageKind> {
+    match event_type {
+        EventType::Note | EventType::Noteon | EventType::Noteoff => Some(MessageKind::Note),
+        EventType::Keypress | EventType::Chanpress => Some(MessageKind::Aftertouch),
+        EventType::Controller
+        | EventType::Control14
+        | EventType::Nonregparam
+        | EventType::Regparam => Some(MessageKind::Controller),
+        EventType::Pgmchange => Some(MessageKind::ProgramChange),
+        EventType::Pitchbend => Some(MessageKind::PitchBend),
+        EventType::Sysex => Some(MessageKind::Sysex),
+        EventType::Clock
+        | EventType::Start
+        | EventType::Continue
+        | EventType::Stop
+        | EventType::Songpos => Some(MessageKind::Clock),
+        EventType::Sensing => Some(MessageKind::Sensing),
+        _ => None,
+    }
+}
+
+/// Channel (0-15) of channel voice messages, `None` for everything else.
+pub fn channel_of(event: &Event) -> Option<u8> {
+    match event.get_type() {
+        EventType::Note | EventType::Noteon | EventType::Noteoff | EventType::Keypress => {
+            event.get_data::<EvNote>().map(|note| note.channel)
+        }
+        EventType::Controller
+        | EventType::Control14
+        | EventType::Nonregparam
+        | EventType::Regparam
+        | EventType::Pgmchange
+        | EventType::Chanpress
+        | EventType::Pitchbend => event.get_data::<EvCtrl>().map(|ctrl| ctrl.channel),
+        _ => None,
+    }
+}
+
+/// Same channel voice message, on another channel (0-15).
+pub fn with_channel(event: &Event, channel: u8) -> Event<'static> {
+    if let Some(note) = event.get_data::<EvNote>() {
+        return Event::new(event.get_type(), &EvNote { channel, ..note });
+    }
+    if let Some(ctrl) = event.get_data::<EvCtrl>() {
+        return Event::new(event.get_type(), &EvCtrl { channel, ..ctrl });
+    }
+    event.clone().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alsa::seq::EvQueueControl;
+
+    fn pipeline(route: &str) -> Pipeline {
+        let route = serde_yaml::from_str(&format!("from: A\nto: B\n{}", route)).unwrap();
+        Pipeline::new(route, None)
+    }
+
+    fn note(event_type: EventType, note: u8, velocity: u8) -> Event<'static> {
+        let data = EvNote { channel: 0, note, velocity, off_velocity: 0, duration: 0 };
+        Event::new(event_type, &data)
+    }
+
+    fn controller(param: u32, value: i32) -> Event<'static> {
+        Event::new(EventType::Controller, &EvCtrl { channel: 0, param, value })
+    }
+
+    /// Type, note and velocity of the note messages sent.
+    fn notes(out: Vec<Output>) -> Vec<(EventType, u8, u8)> {
+        out.iter()
+            .map(|(event, _)| {
+                let data = event.get_data::<EvNote>().unwrap();
+                (event.get_type(), data.note, data.velocity)
+            })
+            .collect()
+    }
+
+    /// Controller number and value of the controller messages sent.
+    fn controllers(out: Vec<Output>) -> Vec<(u32, i32)> {
+        out.iter()
+            .map(|(event, _)| event.get_data::<EvCtrl>().unwrap())
+            .map(|ctrl| (ctrl.param, ctrl.value))
+            .collect()
+    }
+
+    /// Channel (1-16) of each message sent.
+    fn channels(out: Vec<Output>) -> Vec<u8> {
+        out.iter().map(|(event, _)| channel_of(event).unwrap() + 1).collect()
+    }
+
+    #[test]
+    fn filters_channels() {
+        let mut pipeline = pipeline("channels: [1, 10]");
+        let on = note(EventType::Noteon, 60, 100);
+        assert_eq!(channels(pipeline.process(&on)), vec![1]);
+        assert_eq!(channels(pipeline.process(&with_channel(&on, 9))), vec![10]);
+        assert!(pipeline.process(&with_channel(&on, 1)).is_empty());
+        assert!(pipeline.process(&with_channel(&controller(7, 90), 15)).is_empty());
+    }
+
+    #[test]
+    fn remaps_channels() {
+        let mut pipeline = pipeline("channels: [1, 2]\nremap-channels: {1: 10}");
+        let (on, volume) = (note(EventType::Noteon, 60, 100), controller(7, 90));
+        assert_eq!(channels(pipeline.process(&on)), vec![10]);
+        assert_eq!(channels(pipeline.process(&volume)), vec![10]);
+        assert_eq!(channels(pipeline.process(&with_channel(&volume, 1))), vec![2]);
+        let off = pipeline.process(&note(EventType::Noteoff, 60, 0));
+        assert_eq!(off[0].0.get_data::<EvNote>().map(|n| (n.channel, n.note)), Some((9, 60)));
+    }
+
+    #[test]
+    fn transposes_within_range() {
+        let mut pipeline = pipeline("octave: 1\ntranspose: -1");
+        let on = pipeline.process(&note(EventType::Noteon, 60, 100));
+        assert_eq!(notes(on), vec![(EventType::Noteon, 71, 100)]);
+        // would land above 127, dropped along with its release
+        assert!(pipeline.process(&note(EventType::Noteon, 120, 100)).is_empty());
+        assert!(pipeline.process(&note(EventType::Noteoff, 120, 0)).is_empty());
+    }
+
+    #[test]
+    fn releases_follow_their_note_on() {
+        let mut pipeline = pipeline("transpose: 0");
+        pipeline.process(&note(EventType::Noteon, 60, 100));
+        pipeline.set_shift(12);
+        let off = pipeline.process(&note(EventType::Noteoff, 60, 0));
+        assert_eq!(notes(off), vec![(EventType::Noteoff, 60, 0)]);
+        // note on with velocity 0 releases too
+        pipeline.process(&note(EventType::Noteon, 60, 100));
+        pipeline.set_shift(-12);
+        let off = pipeline.process(&note(EventType::Noteon, 60, 0));
+        assert_eq!(notes(off), vec![(EventType::Noteon, 72, 0)]);
+        // aftertouch of a held note goes where the note went
+        pipeline.process(&note(EventType::Noteon, 64, 100));
+        let pressure = pipeline.process(&note(EventType::Keypress, 64, 30));
+        assert_eq!(notes(pressure), vec![(EventType::Keypress, 52, 30)]);
+    }
+
+    #[test]
+    fn velocity_only_on_note_on() {
+        let velocity = VelocityPrefs { fixed: Some(90), ..Default::default() };
+        let route = serde_yaml::from_str("from: A\nto: B").unwrap();
+        let mut pipeline = Pipeline::new(route, Some(velocity));
+        let on = pipeline.process(&note(EventType::Noteon, 60, 10));
+        assert_eq!(notes(on), vec![(EventType::Noteon, 60, 90)]);
+        let off = pipeline.process(&note(EventType::Noteon, 60, 0));
+        assert_eq!(notes(off), vec![(EventType::Noteon, 60, 0)]);
+        let pressure = pipeline.process(&note(EventType::Keypress, 60, 10));
+        assert_eq!(notes(pressure), vec![(EventType::Keypress, 60, 10)]);
+    }
+
+    #[test]
+    fn maps_controllers() {
+        let mut mapping = pipeline(
+            "cc-map:\n\
+             - {from-cc: 1, to-cc: 11, out-range: 0..100}\n\
+             - {from-cc: 1, to-cc: 12, invert: true}",
+        );
+        let out = mapping.process(&controller(1, 127));
+        assert_eq!(controllers(out), vec![(11, 100), (12, 0)]);
+        assert_eq!(controllers(mapping.process(&controller(7, 90))), vec![(7, 90)]);
+        let mut dropping = pipeline("cc-map: [{from-cc: 1}]\nunmapped-cc: Drop");
+        assert_eq!(controllers(dropping.process(&controller(1, 5))), vec![(1, 5)]);
+        assert!(dropping.process(&controller(7, 90)).is_empty());
+    }
+
+    fn clock(event_type: EventType) -> Event<'static> {
+        Event::new(event_type, &EvQueueControl { queue: 0, value: () })
+    }
+
+    /// Ticks sent for each of `count` incoming ticks.
+    fn ticks(pipeline: &mut Pipeline, count: usize) -> Vec<usize> {
+        (0..count).map(|_| pipeline.process(&clock(EventType::Clock)).len()).collect()
+    }
+
+    #[test]
+    fn divides_and_multiplies_clock() {
+        let mut half = pipeline("clock: {divide: 2}");
+        assert_eq!(ticks(&mut half, 4), vec![1, 0, 1, 0]);
+        let mut double = pipeline("clock: {multiply: 2}");
+        assert_eq!(ticks(&mut double, 3), vec![2, 2, 2]);
+        let mut two_thirds = pipeline("clock: {divide: 3, multiply: 2}");
+        assert_eq!(ticks(&mut two_thirds, 6), vec![1, 1, 0, 1, 1, 0]);
+        // the rest goes through once
+        assert_eq!(two_thirds.process(&clock(EventType::Start)).len(), 1);
+    }
+
+    #[test]
+    fn clock_follows_song_position() {
+        let mut quarter = pipeline("clock: {divide: 4}");
+        assert_eq!(ticks(&mut quarter, 2), vec![1, 0]);
+        // one 16th in, two ticks before the next outgoing one
+        let position = EvCtrl { channel: 0, param: 0, value: 1 };
+        quarter.process(&Event::new(EventType::Songpos, &position));
+        assert_eq!(ticks(&mut quarter, 3), vec![0, 0, 1]);
+        quarter.process(&clock(EventType::Start));
+        assert_eq!(ticks(&mut quarter, 2), vec![1, 0]);
+    }
+
+    #[test]
+    fn release_sends_held_notes_off() {
+        let mut pipeline = pipeline("transpose: 2");
+        pipeline.process(&note(EventType::Noteon, 60, 100));
+        pipeline.process(&note(EventType::Noteon, 64, 100));
+        pipeline.process(&note(EventType::Noteoff, 64, 0));
+        let released: Vec<u8> =
+            pipeline.release().iter().map(|e| e.get_data::<EvNote>().unwrap().note).collect();
+        assert_eq!(released, vec![62]);
+        assert!(pipeline.release().is_empty());
+    }
+
+    #[test]
+    fn release_on_remapped_channel() {
+        let mut pipeline = pipeline("remap-channels: {1: 10}");
+        pipeline.process(&note(EventType::Noteon, 60, 100));
+        let released = pipeline.release();
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0].get_type(), EventType::Noteoff);
+        assert_eq!(channel_of(&released[0]), Some(9));
+    }
+
+    #[test]
+    fn note_range_splits() {
+        let mut pipeline = pipeline("notes: C-1..B3");
+        assert_eq!(pipeline.process(&note(EventType::Noteon, 59, 100)).len(), 1);
+        assert!(pipeline.process(&note(EventType::Noteon, 60, 100)).is_empty());
+        // other messages are not split
+        assert_eq!(pipeline.process(&controller(7, 90)).len(), 1);
+    }
+}
@@ -1,56 +1,63 @@
 use std::io;
 use std::sync::mpsc;
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
-};
 use std::thread;
 use std::time::Duration;
 
-use crate::ports::{new_port_sub, SYSTEM_ANNOUNCE_PORT, SYSTEM_CLIENT};
+use crate::control::{Reply, Request};
+use crate::ports::{new_port_sub, open_seq, AlsaMidi, SYSTEM_ANNOUNCE_PORT, SYSTEM_CLIENT};
 use alsa::seq;
-use alsa::seq::{Addr, PortSubscribe};
+use alsa::seq::Addr;
 use std::error::Error;
 use std::ffi::CString;
-use std::sync::mpsc::SendError;
 use termion::event::Key;
 use termion::input::TermRead;
 
 pub enum Event {
     KeyPressed(Key),
     MidiPortsChanged(Vec<String>),
+    Control(Request, mpsc::Sender<Reply>),
+    /// Time to refresh whatever changes on its own
+    Tick,
+    /// A port sends more messages per second than allowed, probably stuck in a feedback loop
+    Runaway(Addr, u32),
+    /// An event source the app can't do without stopped, see `vital`
+    SourceFailed(String),
 }
 
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
 pub struct AppEvents {
-    sources: Vec<thread::JoinHandle<()>>,
+    _sources: Vec<thread::JoinHandle<()>>,
+    tx: mpsc::Sender<Event>,
     rx: mpsc::Receiver<Event>,
 }
 
-pub type EventSource = fn(mpsc::Sender<Event>) -> Result<(), Box<dyn Error>>;
+pub type EventSource = Box<dyn FnOnce(mpsc::Sender<Event>) -> Result<(), Box<dyn Error>> + Send>;
 
 pub fn keyboard(tx: mpsc::Sender<Event>) -> Result<(), Box<dyn Error>> {
     let stdin = io::stdin();
-    for evt in stdin.keys() {
-        if let Ok(key) = evt {
-            tx.send(Event::KeyPressed(key))?;
-        }
+    for key in stdin.keys().flatten() {
+        tx.send(Event::KeyPressed(key))?;
     }
     Ok(())
 }
 
+pub fn ticker(tx: mpsc::Sender<Event>) -> Result<(), Box<dyn Error>> {
+    loop {
+        thread::sleep(TICK_INTERVAL);
+        tx.send(Event::Tick)?;
+    }
+}
+
 pub fn alsa_announce(tx: mpsc::Sender<Event>) -> Result<(), Box<dyn Error>> {
-    // err = snd_seq_open(&seq, "autoroute2-watch", SND_SEQ_OPEN_DUPLEX, 0);
-    // err = snd_seq_set_client_name(seq, "aseqdump");
-    let seq = seq::Seq::open(None, None, false)?;
+    let seq = open_seq()?;
 
     let port_name = CString::new("Autoroute2 System Announce Monitor")?;
-    let listen_port = seq
-        .create_simple_port(
-            &port_name,
-            seq::PortCap::WRITE | seq::PortCap::SUBS_WRITE,
-            seq::PortType::MIDI_GENERIC | seq::PortType::APPLICATION,
-        )
-        .expect("Announce monitor port");
+    let listen_port = seq.create_simple_port(
+        &port_name,
+        seq::PortCap::WRITE | seq::PortCap::SUBS_WRITE,
+        seq::PortType::MIDI_GENERIC | seq::PortType::APPLICATION,
+    )?;
 
     // for (i = 0; i < port_count; ++i) {
     //     err = snd_seq_connect_from(seq, 0, ports[i].client, ports[i].port);
@@ -69,23 +76,24 @@ pub fn alsa_announce(tx: mpsc::Sender<Event>) -> Result<(), Box<dyn Error>> {
         // err = snd_seq_event_input(seq, &event);
         match input.event_input()?.get_type() {
             seq::EventType::PortChange | seq::EventType::PortExit | seq::EventType::PortStart => {
-                let mut ports = vec![];
-                for client in seq::ClientIter::new(&seq) {
-                    if client.get_client() == SYSTEM_CLIENT {
-                        continue;
-                    }
-                    for p in seq::PortIter::new(&seq, client.get_client()) {
-                        if let Ok(name) = p.get_name() {
-                            ports.push(name.to_owned());
-                        }
-                    }
-                }
+                let ports = AlsaMidi::new()?.ports.iter().map(|p| p.id()).collect();
                 tx.send(Event::MidiPortsChanged(ports))?;
             }
             _ => {}
         }
     }
-    Ok(())
+}
+
+/// Report the source stopping, for whatever reason, as a `SourceFailed` event.
+pub fn vital(source: EventSource) -> EventSource {
+    Box::new(move |tx: mpsc::Sender<Event>| {
+        let reason = match source(tx.clone()) {
+            Ok(_) => "Event source ended".to_string(),
+            Err(e) => e.to_string(),
+        };
+        tx.send(Event::SourceFailed(reason))?;
+        Ok(())
+    })
 }
 
 impl AppEvents {
@@ -100,10 +108,19 @@ impl AppEvents {
                 };
             }))
         }
-        AppEvents { sources: src_handles, rx }
+        AppEvents { _sources: src_handles, tx, rx }
+    }
+
+    /// For event sources that are not threads of their own.
+    pub fn sender(&self) -> mpsc::Sender<Event> {
+        self.tx.clone()
     }
 
     pub fn next(&self) -> Result<Event, mpsc::RecvError> {
         self.rx.recv()
     }
+
+    pub fn next_timeout(&self, timeout: Duration) -> Result<Event, mpsc::RecvTimeoutError> {
+        self.rx.recv_timeout(timeout)
+    }
 }
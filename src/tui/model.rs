@@ -1,10 +1,12 @@
-use crate::ports::AlsaMidi;
+use crate::control::{self, Request, Transport};
+use crate::panic;
 use crate::prefs::UserPrefs;
 use crate::tui::event::{AppEvents, Event};
 use crate::tui::view;
 use itertools::Itertools;
 use std::error::Error;
-use std::io;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
 use termion::event::Key;
 use tui::backend::Backend;
 use tui::widgets::ListState;
@@ -16,7 +18,7 @@ pub struct TabsState<'a> {
 }
 
 impl<'a> TabsState<'a> {
-    pub fn new(titles: Vec<&'a str>) -> TabsState {
+    pub fn new(titles: Vec<&'a str>) -> TabsState<'a> {
         TabsState { titles, index: 0 }
     }
     pub fn next(&mut self) {
@@ -91,6 +93,12 @@ pub struct Model<'a> {
     pub tabs: TabsState<'a>,
     pub ports: SingleSelectList,
     pub prefs: UserPrefs,
+    /// Outcome of the last command sent to the daemon
+    pub status: String,
+    /// Clock source and tempo, as measured by the daemon
+    pub tempo: String,
+    /// Answer to the tempo query in flight, the daemon may be slow to reply
+    tempo_query: Option<Receiver<String>>,
 }
 
 impl<'a> Model<'a> {
@@ -100,9 +108,54 @@ impl<'a> Model<'a> {
             tabs: TabsState::new(vec!["Ports"]),
             ports: SingleSelectList::with_items(ports),
             prefs,
+            status: String::new(),
+            tempo: String::new(),
+            tempo_query: None,
         }
     }
 
+    /// Transpose routes from the selected port, through the daemon.
+    pub fn shift(&mut self, semitones: i32) {
+        let from = match self.ports.state.selected() {
+            Some(idx) => self.ports.items[idx].clone(),
+            None => return,
+        };
+        self.send(Request::Shift { from, semitones })
+    }
+
+    /// Silence every output, daemon or not.
+    fn panic(&mut self) {
+        self.status = match panic::all_outputs(&self.prefs) {
+            Ok(count) => format!("Silenced {} ports", count),
+            Err(e) => e.to_string(),
+        };
+    }
+
+    /// Pass a request on to the daemon, showing its reply.
+    fn send(&mut self, request: Request) {
+        self.status = match control::send(&self.prefs.state_dir(), &request) {
+            Ok(reply) => reply,
+            Err(e) => e.to_string(),
+        };
+    }
+
+    /// Show the last tempo the daemon told, asking again once it has answered.
+    pub fn refresh_tempo(&mut self) {
+        if let Some(query) = &self.tempo_query {
+            match query.try_recv() {
+                Ok(tempo) => self.tempo = tempo,
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => {}
+            }
+        }
+        let (tx, rx) = mpsc::channel();
+        let state_dir = self.prefs.state_dir();
+        thread::spawn(move || {
+            let _ = tx.send(control::send(&state_dir, &Request::Tempo).unwrap_or_default());
+        });
+        self.tempo_query = Some(rx);
+    }
+
     pub fn refresh_ports(&mut self, ports: Vec<String>) {
         self.ports.update_items(ports);
     }
@@ -117,10 +170,15 @@ impl<'a> Model<'a> {
 
             match events.next()? {
                 Event::KeyPressed(key) => match key {
-                    Key::Char(c) => match c {
-                        'q' => return Ok(()),
-                        _ => {}
-                    },
+                    Key::Char('q') => return Ok(()),
+                    Key::Char('+') => self.shift(12),
+                    Key::Char('-') => self.shift(-12),
+                    Key::Char(']') => self.shift(1),
+                    Key::Char('[') => self.shift(-1),
+                    Key::Char('s') => self.send(Request::Clock(Transport::Start)),
+                    Key::Char('x') => self.send(Request::Clock(Transport::Stop)),
+                    Key::Char('c') => self.send(Request::Clock(Transport::Continue)),
+                    Key::Char('p') => self.panic(),
                     Key::Up => self.ports.previous(),
                     Key::Down => self.ports.next(),
                     Key::Left => self.tabs.previous(),
@@ -128,6 +186,11 @@ impl<'a> Model<'a> {
                     _ => {}
                 },
                 Event::MidiPortsChanged(ports) => self.refresh_ports(ports),
+                Event::Tick => self.refresh_tempo(),
+                Event::Runaway(..) | Event::SourceFailed(_) => {}
+                Event::Control(_, reply) => {
+                    let _ = reply.send(Err("Not a daemon".to_string()));
+                }
             }
         }
     }
@@ -3,11 +3,8 @@ use tui::{
     layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
     symbols,
-    widgets::canvas::{Canvas, Line, Map, MapResolution, Rectangle},
-    widgets::{
-        Axis, BarChart, Block, Borders, Chart, Dataset, Gauge, List, Paragraph, Row, Sparkline,
-        Table, Tabs, Text,
-    },
+    widgets::canvas::{Canvas, Map, MapResolution, Rectangle},
+    widgets::{Block, Borders, List, Tabs, Text},
     Frame,
 };
 
@@ -17,8 +14,12 @@ pub fn draw_root<B: Backend>(f: &mut Frame<B>, app: &mut Model) {
     let chunks = Layout::default()
         .constraints([Constraint::Length(3), Constraint::Min(0)].as_ref())
         .split(f.size());
+    let title = match app.tempo.as_str() {
+        "" => app.title.to_string(),
+        tempo => format!("{} - {}", app.title, tempo),
+    };
     let tabs = Tabs::default()
-        .block(Block::default().borders(Borders::ALL).title(app.title))
+        .block(Block::default().borders(Borders::ALL).title(&title))
         .titles(&app.tabs.titles)
         .style(Style::default().fg(Color::Green))
         .highlight_style(Style::default().fg(Color::Yellow))
@@ -56,9 +57,13 @@ fn draw_charts<B: Backend>(f: &mut Frame<B>, model: &mut Model, area: Rect) {
         Layout::default().constraints(constraints).direction(Direction::Horizontal).split(area);
 
     // Draw tasks
-    let tasks = model.ports.items.iter().map(|i| Text::raw(i));
+    let tasks = model.ports.items.iter().map(Text::raw);
     let tasks = List::new(tasks)
-        .block(Block::default().borders(Borders::ALL).title("List"))
+        .block(Block::default().borders(Borders::ALL).title(if model.status.is_empty() {
+            "Ports"
+        } else {
+            &model.status
+        }))
         .highlight_style(Style::default().fg(Color::Yellow).modifier(Modifier::BOLD))
         .highlight_symbol("> ");
     f.render_stateful_widget(tasks, chunks[0], &mut model.ports.state);
@@ -172,7 +177,7 @@ fn draw_charts<B: Backend>(f: &mut Frame<B>, model: &mut Model, area: Rect) {
     // }
 }
 
-fn draw_second_tab<B: Backend>(f: &mut Frame<B>, app: &mut Model, area: Rect) {
+fn draw_second_tab<B: Backend>(f: &mut Frame<B>, _app: &mut Model, area: Rect) {
     let chunks = Layout::default()
         .constraints([Constraint::Percentage(30), Constraint::Percentage(70)].as_ref())
         .direction(Direction::Horizontal)
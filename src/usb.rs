@@ -0,0 +1,38 @@
+use std::fs;
+use std::path::Path;
+
+/// Sequencer clients of sound card N are numbered 16 + 4 * N by the kernel.
+const CARD_CLIENTS_BASE: i32 = 16;
+const CLIENTS_PER_CARD: i32 = 4;
+const DYNAMIC_CLIENTS_BASE: i32 = 128;
+
+/// Where a USB MIDI device is plugged in, as reported by sysfs.
+#[derive(Debug, PartialEq, Clone)]
+pub struct UsbInfo {
+    /// Bus & port chain, e.g. `1-1.2`. Stays the same as long as the device
+    /// goes back in the same hub port.
+    pub path: String,
+    pub serial: Option<String>,
+}
+
+/// Look up the USB device behind an ALSA sequencer client.
+/// Returns `None` for software clients and non-USB cards.
+pub fn for_client(client: i32) -> Option<UsbInfo> {
+    if !(CARD_CLIENTS_BASE..DYNAMIC_CLIENTS_BASE).contains(&client) {
+        return None;
+    }
+    let card = (client - CARD_CLIENTS_BASE) / CLIENTS_PER_CARD;
+
+    // resolves to the USB interface, e.g. .../usb1/1-1/1-1.2/1-1.2:1.0
+    let interface = fs::canonicalize(format!("/sys/class/sound/card{}/device", card)).ok()?;
+    if !interface.to_string_lossy().contains("/usb") {
+        return None;
+    }
+    let device = interface.parent()?;
+    let path = device.file_name()?.to_string_lossy().into_owned();
+    Some(UsbInfo { path, serial: read_attr(device, "serial") })
+}
+
+fn read_attr(device: &Path, attr: &str) -> Option<String> {
+    fs::read_to_string(device.join(attr)).ok().map(|s| s.trim().to_string())
+}